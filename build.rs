@@ -0,0 +1,37 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// With the xz-threaded feature, enable the multithreaded xz decoder only
+// if we're linking against liblzma >= 5.4, which added it.  Otherwise xz
+// is decoded single-threaded.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(xz_threaded)");
+    #[cfg(feature = "xz-threaded")]
+    {
+        // lzma-sys reports an include directory only when it builds its
+        // bundled liblzma, which is 5.2
+        let bundled = std::env::var_os("DEP_LZMA_INCLUDE").is_some();
+        if !bundled
+            && pkg_config::Config::new()
+                .atleast_version("5.4")
+                .cargo_metadata(false)
+                .probe("liblzma")
+                .is_ok()
+        {
+            println!("cargo:rustc-cfg=xz_threaded");
+        }
+    }
+}
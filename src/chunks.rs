@@ -0,0 +1,67 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bytes::Bytes;
+
+use crate::io::{self, BufRead};
+use crate::DecompressReader;
+
+/// Iterator over chunks of decompressed data, returned by
+/// `DecompressReader::chunks()`
+#[derive(Debug)]
+pub struct Chunks<'r, 'a, R: BufRead> {
+    reader: &'r mut DecompressReader<'a, R>,
+    done: bool,
+}
+
+impl<'r, 'a, R: BufRead> Chunks<'r, 'a, R> {
+    pub(crate) fn new(reader: &'r mut DecompressReader<'a, R>) -> Self {
+        Self {
+            reader,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Chunks<'_, '_, R> {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let ret = self.reader.next_chunk().transpose();
+        // stop after EOF or the first error
+        if !matches!(ret, Some(Ok(_))) {
+            self.done = true;
+        }
+        ret
+    }
+}
+
+impl<R: BufRead> core::iter::FusedIterator for Chunks<'_, '_, R> {}
+
+/// Each poll performs blocking I/O on the underlying reader, so the stream
+/// should be polled from a context where blocking is acceptable.
+#[cfg(feature = "stream")]
+impl<R: BufRead> futures_core::Stream for Chunks<'_, '_, R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        std::task::Poll::Ready(self.get_mut().next())
+    }
+}
@@ -12,36 +12,66 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::BufRead;
+#[cfg(feature = "std")]
+use std::io::Write;
 
-use crate::{DecompressReader, PeekReader, Result};
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+use crate::format::zstd_common::SkippableHandler;
+use crate::io::BufRead;
+use crate::progress::ProgressHandler;
+#[cfg(feature = "std")]
+use crate::DecompressWriter;
+use crate::{DecompressReader, Members, PeekReader, Progress, ProgressInterval, Result};
 
 #[derive(Clone, Debug)]
 pub struct DecompressBuilder {
     pub(crate) trailing_data: bool,
     pub(crate) uncompressed: bool,
+    // set by Members; readers stop at the end of the first member
+    pub(crate) single_member: bool,
+    pub(crate) progress: Option<ProgressHandler>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_ratio: Option<u64>,
 
-    #[cfg(feature = "bzip2")]
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
     pub(crate) bzip2: bool,
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub(crate) bzip2_threads: u32,
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub(crate) bzip2_small: bool,
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub(crate) bzip2_block_crcs: bool,
     #[cfg(feature = "gzip")]
     pub(crate) gzip: bool,
-    #[cfg(feature = "xz")]
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
     pub(crate) xz: bool,
-    #[cfg(feature = "zstd")]
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    pub(crate) xz_threads: u32,
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    pub(crate) xz_thread_memlimit: Option<u64>,
+    #[cfg(feature = "xz")]
+    pub(crate) xz_memlimit: Option<u64>,
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
     pub(crate) zstd: bool,
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub(crate) zstd_threads: u32,
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub(crate) zstd_skippable: Option<SkippableHandler>,
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub(crate) zstd_window_log_max: Option<u32>,
 }
 
 impl DecompressBuilder {
     pub fn new() -> Self {
         Self {
             // uncompressed disabled by default
-            #[cfg(feature = "bzip2")]
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
             bzip2: true,
             #[cfg(feature = "gzip")]
             gzip: true,
-            #[cfg(feature = "xz")]
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
             xz: true,
-            #[cfg(feature = "zstd")]
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
             zstd: true,
             ..Self::none()
         }
@@ -51,15 +81,37 @@ impl DecompressBuilder {
         Self {
             trailing_data: false,
             uncompressed: false,
+            single_member: false,
+            progress: None,
+            max_size: None,
+            max_ratio: None,
 
-            #[cfg(feature = "bzip2")]
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
             bzip2: false,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            bzip2_threads: 1,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            bzip2_small: false,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            bzip2_block_crcs: false,
             #[cfg(feature = "gzip")]
             gzip: false,
-            #[cfg(feature = "xz")]
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
             xz: false,
-            #[cfg(feature = "zstd")]
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            xz_threads: 1,
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            xz_thread_memlimit: None,
+            #[cfg(feature = "xz")]
+            xz_memlimit: None,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
             zstd: false,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            zstd_threads: 1,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            zstd_skippable: None,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            zstd_window_log_max: None,
         }
     }
 
@@ -74,6 +126,18 @@ impl DecompressBuilder {
         DecompressReader::new_full(source, self.clone())
     }
 
+    /// Iterate over the members of a concatenated input: gzip members,
+    /// bzip2 or xz streams, or zstd frames.  Unlike build(), the format
+    /// isn't detected until the first member is requested.
+    pub fn members<'a, R: BufRead>(&self, source: R) -> Members<'a, R> {
+        Members::new(PeekReader::new(source), self.clone())
+    }
+
+    #[cfg(feature = "std")]
+    pub fn build_writer<'a, W: Write>(&self, sink: W) -> DecompressWriter<'a, W> {
+        DecompressWriter::new_full(sink, self.clone())
+    }
+
     pub fn trailing_data(&mut self, enable: bool) -> &mut Self {
         self.trailing_data = enable;
         self
@@ -84,29 +148,149 @@ impl DecompressBuilder {
         self
     }
 
-    #[cfg(feature = "bzip2")]
+    /// Call this function with the position of a DecompressReader or
+    /// Members as it returns data, at the given interval, and once more
+    /// when it reaches the end of the input.  Readers built from this
+    /// config call it on whichever thread is reading.
+    pub fn progress<F>(&mut self, interval: ProgressInterval, callback: F) -> &mut Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(ProgressHandler::new(interval, callback));
+        self
+    }
+
+    /// Fail with DecompressError::SizeLimitExceeded as soon as the
+    /// decompressed output exceeds this many bytes.  Applies to
+    /// DecompressReader, to DecompressWriter, which writes output up to
+    /// the limit to its sink, and to Members, across all members together.
+    pub fn max_size(&mut self, limit: u64) -> &mut Self {
+        self.max_size = Some(limit);
+        self
+    }
+
+    /// Fail with DecompressError::RatioLimitExceeded as soon as the
+    /// decompressed output is more than this many times the compressed
+    /// input consumed.  Applies to the same readers and writers as
+    /// max_size().  The first MiB of output is always allowed, since
+    /// decoders can produce some output before consuming much input.
+    pub fn max_ratio(&mut self, ratio: u64) -> &mut Self {
+        self.max_ratio = Some(ratio);
+        self
+    }
+
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
     pub fn bzip2(&mut self, enable: bool) -> &mut Self {
         self.bzip2 = enable;
         self
     }
 
+    /// Decode bzip2 blocks in parallel on this many worker threads.
+    /// Each worker holds up to two decompressed blocks, each up to
+    /// several MB.
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub fn bzip2_threads(&mut self, threads: u32) -> &mut Self {
+        self.bzip2_threads = threads;
+        self
+    }
+
+    /// Use libbzip2's small decompression mode, which needs less than half
+    /// the memory but is about twice as slow.
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub fn bzip2_small(&mut self, enable: bool) -> &mut Self {
+        self.bzip2_small = enable;
+        self
+    }
+
+    /// Collect block CRCs and combined CRCs for
+    /// DecompressReader::bzip2_stream().  The parallel decoder always
+    /// collects them; the serial decoder has to scan the compressed input,
+    /// which slows decoding, so it only does so if this is enabled.
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub fn bzip2_block_crcs(&mut self, enable: bool) -> &mut Self {
+        self.bzip2_block_crcs = enable;
+        self
+    }
+
     #[cfg(feature = "gzip")]
     pub fn gzip(&mut self, enable: bool) -> &mut Self {
         self.gzip = enable;
         self
     }
 
-    #[cfg(feature = "xz")]
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
     pub fn xz(&mut self, enable: bool) -> &mut Self {
         self.xz = enable;
         self
     }
 
-    #[cfg(feature = "zstd")]
+    /// Decode xz with up to this many threads.  Only multi-block streams
+    /// that record block sizes in their headers, such as those written by
+    /// `xz -T`, can be decoded in parallel.  Without the `xz-threaded`
+    /// feature and liblzma >= 5.4, xz is always decoded single-threaded.
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    pub fn xz_threads(&mut self, threads: u32) -> &mut Self {
+        self.xz_threads = threads;
+        self
+    }
+
+    /// Use fewer xz decoder threads if necessary to stay under this many
+    /// bytes of memory.  Defaults to a quarter of physical memory.
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    pub fn xz_thread_memlimit(&mut self, limit: u64) -> &mut Self {
+        self.xz_thread_memlimit = Some(limit);
+        self
+    }
+
+    /// Fail with DecompressError::XzMemlimitExceeded if decoding an xz
+    /// stream would need more than this many bytes of memory.  By default
+    /// there's no limit.  Only available with liblzma, since the pure-Rust
+    /// backend can't enforce it.
+    #[cfg(feature = "xz")]
+    pub fn xz_memlimit(&mut self, limit: u64) -> &mut Self {
+        self.xz_memlimit = Some(limit);
+        self
+    }
+
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
     pub fn zstd(&mut self, enable: bool) -> &mut Self {
         self.zstd = enable;
         self
     }
+
+    /// Decode independent zstd frames in parallel on this many worker
+    /// threads.  This helps with multi-frame files such as those written
+    /// by pzstd.  Frames that don't record their decompressed size, or
+    /// that are larger than 16 MiB, are decoded serially.  The pure-Rust
+    /// `zstd-rust` backend always decodes serially.
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub fn zstd_threads(&mut self, threads: u32) -> &mut Self {
+        self.zstd_threads = threads;
+        self
+    }
+
+    /// Fail with DecompressError::ZstdWindowTooLarge on frames whose window
+    /// is larger than 2^window_log_max bytes.  The window dominates the
+    /// decoder's memory use.  libzstd's default is 27, and it accepts
+    /// values from 10 to 31.
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub fn zstd_window_log_max(&mut self, window_log_max: u32) -> &mut Self {
+        self.zstd_window_log_max = Some(window_log_max);
+        self
+    }
+
+    /// Call this function with the magic variant (0 to 15) and payload of
+    /// each zstd skippable frame, in stream order, as the frame is
+    /// decompressed.  Payloads are buffered in full, so they can use as
+    /// much memory as the size in their header, up to 4 GiB.
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub fn zstd_skippable_frames<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(u8, &[u8]) + Send + Sync + 'static,
+    {
+        self.zstd_skippable = Some(SkippableHandler::new(callback));
+        self
+    }
 }
 
 impl Default for DecompressBuilder {
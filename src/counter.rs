@@ -0,0 +1,87 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Accounting for data returned by DecompressReader and Members: byte
+// counts, limits, and progress reports.  Compressed counts come from the
+// bytes consumed from the PeekReader, which the caller passes in.
+
+use crate::limits::Limits;
+use crate::progress::{Progress, ProgressState};
+use crate::{CompressionFormat, DecompressBuilder, Result};
+
+#[derive(Debug)]
+pub(crate) struct Counter {
+    limits: Limits,
+    progress: Option<ProgressState>,
+    // source position when we started decoding it
+    in_start: u64,
+    total_out: u64,
+}
+
+impl Counter {
+    pub(crate) fn new(config: &DecompressBuilder, in_start: u64) -> Self {
+        Self {
+            limits: Limits::new(config),
+            progress: config.progress.as_ref().map(ProgressState::new),
+            in_start,
+            total_out: 0,
+        }
+    }
+
+    /// Compressed bytes consumed, given the source position.
+    pub(crate) fn total_in(&self, consumed: u64) -> u64 {
+        consumed - self.in_start
+    }
+
+    pub(crate) fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    /// Limit a read to at most one byte past the size limit.
+    pub(crate) fn read_len(&self, len: usize) -> usize {
+        len.min(self.limits.remaining(self.total_out).saturating_add(1))
+    }
+
+    /// Fail if returning this many more decompressed bytes would exceed a
+    /// configured limit.
+    pub(crate) fn check(&self, consumed: u64, count: usize) -> Result<()> {
+        self.limits
+            .check(self.total_in(consumed), self.total_out + count as u64)
+    }
+
+    /// Count decompressed bytes returned to the caller.
+    pub(crate) fn advance(&mut self, count: usize) {
+        self.total_out += count as u64;
+    }
+
+    pub(crate) fn report(
+        &mut self,
+        consumed: u64,
+        member: u64,
+        format: CompressionFormat,
+        done: bool,
+    ) {
+        if self.progress.is_none() {
+            return;
+        }
+        let progress = Progress {
+            compressed: self.total_in(consumed),
+            decompressed: self.total_out,
+            member,
+            format,
+            done,
+        };
+        self.progress.as_mut().unwrap().update(&progress);
+    }
+}
@@ -12,18 +12,118 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use thiserror::Error;
+#[cfg(not(feature = "std"))]
+use core::error::Error as StdError;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error as StdError;
 
-#[derive(Debug, Error)]
+use crate::io;
+
+#[derive(Debug)]
 #[non_exhaustive]
 pub enum DecompressError {
-    #[error("unrecognized compression format")]
     UnrecognizedFormat,
-    #[error("I/O error: {source}")]
+    OutputTooSmall,
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    XzStreamPadding,
+    /// An xz stream needs more memory than DecompressBuilder::xz_memlimit()
+    /// allows.
+    #[cfg(feature = "xz")]
+    XzMemlimitExceeded {
+        limit: u64,
+    },
+    /// A zstd frame's window is larger than
+    /// DecompressBuilder::zstd_window_log_max() allows.
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    ZstdWindowTooLarge {
+        window_size: u64,
+        window_log_max: u32,
+    },
+    /// The decompressed data exceeded DecompressBuilder::max_size().
+    SizeLimitExceeded {
+        limit: u64,
+    },
+    /// The decompressed data exceeded DecompressBuilder::max_ratio() times
+    /// the compressed data.
+    RatioLimitExceeded {
+        limit: u64,
+    },
     Io {
-        #[from]
-        source: std::io::Error,
+        source: io::Error,
     },
 }
 
-pub type Result<T> = std::result::Result<T, DecompressError>;
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DecompressError::*;
+        match self {
+            UnrecognizedFormat => write!(f, "unrecognized compression format"),
+            OutputTooSmall => write!(f, "output buffer too small for decompressed data"),
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            XzStreamPadding => write!(f, "xz stream padding is not a multiple of 4 bytes"),
+            #[cfg(feature = "xz")]
+            XzMemlimitExceeded { limit } => {
+                write!(
+                    f,
+                    "xz stream needs more than xz_memlimit of {} bytes",
+                    limit
+                )
+            }
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            ZstdWindowTooLarge {
+                window_size,
+                window_log_max,
+            } => write!(
+                f,
+                "zstd window of {} bytes exceeds zstd_window_log_max of {}",
+                window_size, window_log_max
+            ),
+            SizeLimitExceeded { limit } => {
+                write!(f, "decompressed data exceeds limit of {} bytes", limit)
+            }
+            RatioLimitExceeded { limit } => {
+                write!(
+                    f,
+                    "decompressed data exceeds {} times compressed data",
+                    limit
+                )
+            }
+            Io { source } => write!(f, "I/O error: {}", source),
+        }
+    }
+}
+
+impl StdError for DecompressError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Io { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecompressError {
+    fn from(err: io::Error) -> Self {
+        // unwrap errors that we wrapped in io::Error for the Read trait
+        if err
+            .get_ref()
+            .map(|inner| inner.is::<DecompressError>())
+            .unwrap_or(false)
+        {
+            return *err.into_inner().unwrap().downcast().unwrap();
+        }
+        DecompressError::Io { source: err }
+    }
+}
+
+impl From<DecompressError> for io::Error {
+    fn from(err: DecompressError) -> Self {
+        match err {
+            DecompressError::Io { source } => source,
+            _ => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, DecompressError>;
@@ -12,62 +12,542 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use bzip2::bufread::BzDecoder;
+use bzip2::{Decompress, Status};
 use std::fmt;
-use std::io::{self, BufRead, Read};
+use std::io::{self, BufRead, Error, ErrorKind, Read, Write};
 
-use crate::{FormatReader, PeekReader, Result};
+use crate::format::bzip2_parallel::ParallelDecoder;
+use crate::format::{read_into, OutputBuffer, OUTPUT_BUF_SIZE};
+use crate::{DecompressBuilder, FormatReader, FormatWriter, PeekReader, Result};
+
+pub(crate) const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+pub(crate) const EOS_MAGIC: u64 = 0x1772_4538_5090;
+pub(crate) const MAGIC_BITS: usize = 48;
+pub(crate) const CRC_BITS: usize = 32;
+
+/// Structure of a bzip2 stream, as read so far.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Bzip2Stream {
+    /// Index of the stream in the input, starting from 0.
+    pub index: u64,
+    /// Block size level from the stream header, 1 to 9.
+    pub level: u8,
+    /// Stored CRCs of the blocks read so far.
+    pub block_crcs: Vec<u32>,
+    /// Stored combined CRC, once the end of the stream has been read.
+    pub combined_crc: Option<u32>,
+}
+
+impl Bzip2Stream {
+    pub(crate) fn new(index: u64, header: &[u8]) -> Self {
+        Self {
+            index,
+            level: header[3] - b'0',
+            block_crcs: Vec::new(),
+            combined_crc: None,
+        }
+    }
+
+    /// Number of blocks read so far.
+    pub fn blocks(&self) -> usize {
+        self.block_crcs.len()
+    }
+
+    /// The block magic can occur by chance inside compressed data, which
+    /// looks like an extra block.  If the block CRCs don't produce the
+    /// combined CRC, try dropping each block in turn.
+    fn repair(&mut self) {
+        let expected = match self.combined_crc {
+            Some(crc) => crc,
+            None => return,
+        };
+        if combine_crcs(&self.block_crcs) == expected {
+            return;
+        }
+        for i in 1..self.block_crcs.len() {
+            let mut crcs = self.block_crcs.clone();
+            crcs.remove(i);
+            if combine_crcs(&crcs) == expected {
+                self.block_crcs = crcs;
+                return;
+            }
+        }
+    }
+}
 
 pub(crate) struct Bzip2Reader<R: BufRead> {
+    decoder: Decoder<R>,
+    buf: OutputBuffer,
+    // stop at the end of the first stream
+    single_stream: bool,
+    // libbzip2 small decompression mode
+    small: bool,
+}
+
+enum Decoder<R: BufRead> {
     // needs to be Option so we can replace the decoder
-    decompressor: Option<BzDecoder<PeekReader<R>>>,
+    Serial(Option<StreamDecoder<Source<R>>>),
+    Parallel(PeekReader<R>, ParallelDecoder),
+}
+
+/// Idle decoder state, kept for reuse with another source.
+pub(crate) struct Bzip2State {
+    buf: OutputBuffer,
+    parallel: Option<ParallelDecoder>,
 }
 
 impl<R: BufRead + fmt::Debug> fmt::Debug for Bzip2Reader<R> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Bzip2Reader").finish_non_exhaustive()
+        f.debug_struct("Bzip2Reader")
+            .field("buf", &self.buf)
+            .finish_non_exhaustive()
     }
 }
 
 impl<R: BufRead> Bzip2Reader<R> {
     pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
-        Ok(has_magic(source)?)
+        Ok(has_magic(source.peek(4)?))
     }
 
-    pub(crate) fn new(source: PeekReader<R>) -> Self {
-        Self {
-            decompressor: Some(BzDecoder::new(source)),
+    pub(crate) fn with_state(
+        mut source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<Bzip2State>,
+    ) -> Result<Self> {
+        // libbzip2 allocates per stream, so there's no serial decoder to
+        // reuse
+        let (buf, parallel) = match state {
+            Some(state) => (state.buf, state.parallel),
+            None => (OutputBuffer::default(), None),
+        };
+        let decoder = match parallel {
+            Some(parallel) => Decoder::Parallel(source, parallel),
+            None if config.bzip2_threads > 1 => Decoder::Parallel(
+                source,
+                ParallelDecoder::new(
+                    config.bzip2_threads,
+                    config.single_member,
+                    config.bzip2_small,
+                )?,
+            ),
+            None => {
+                let mut scanner = Scanner::default();
+                scanner.start_stream(source.peek(4)?);
+                let source = Source {
+                    source,
+                    scanner,
+                    scan: config.bzip2_block_crcs,
+                };
+                Decoder::Serial(Some(StreamDecoder::new(source, config.bzip2_small)))
+            }
+        };
+        Ok(Self {
+            decoder,
+            buf,
+            single_stream: config.single_member,
+            small: config.bzip2_small,
+        })
+    }
+
+    pub(crate) fn stream(&self) -> Option<&Bzip2Stream> {
+        match &self.decoder {
+            Decoder::Serial(d) => d.as_ref().unwrap().get_ref().scanner.stream.as_ref(),
+            Decoder::Parallel(_, parallel) => parallel.stream(),
         }
     }
+
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, Bzip2State) {
+        self.buf.clear();
+        let (source, parallel) = match self.decoder {
+            Decoder::Serial(d) => (d.unwrap().into_inner().source, None),
+            Decoder::Parallel(source, mut parallel) => {
+                parallel.reset();
+                (source, Some(parallel))
+            }
+        };
+        let state = Bzip2State {
+            buf: self.buf,
+            parallel,
+        };
+        (source, state)
+    }
 }
 
 impl<R: BufRead> FormatReader<R> for Bzip2Reader<R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        match &self.decoder {
+            Decoder::Serial(d) => &d.as_ref().unwrap().get_ref().source,
+            Decoder::Parallel(source, _) => source,
+        }
+    }
+
     fn get_mut(&mut self) -> &mut PeekReader<R> {
-        self.decompressor.as_mut().unwrap().get_mut()
+        match &mut self.decoder {
+            Decoder::Serial(d) => &mut d.as_mut().unwrap().get_mut().source,
+            Decoder::Parallel(source, _) => source,
+        }
     }
 
     fn into_inner(self) -> PeekReader<R> {
-        self.decompressor.unwrap().into_inner()
+        match self.decoder {
+            Decoder::Serial(d) => d.unwrap().into_inner().source,
+            Decoder::Parallel(source, _) => source,
+        }
+    }
+
+    fn member(&self) -> u64 {
+        self.stream().map(|stream| stream.index).unwrap_or(0)
     }
 }
 
-impl<R: BufRead> Read for Bzip2Reader<R> {
-    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
-        let count = self.decompressor.as_mut().unwrap().read(out)?;
-        if count == 0 && has_magic(self.get_mut())? {
+impl<R: BufRead> Bzip2Reader<R> {
+    fn decompress(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let decompressor = match &mut self.decoder {
+            Decoder::Serial(d) => d,
+            Decoder::Parallel(..) => {
+                if !self.next_block()? {
+                    return Ok(0);
+                }
+                return Ok(self.buf.read(out));
+            }
+        };
+        let count = decompressor.as_mut().unwrap().read(out)?;
+        if count == 0 && !out.is_empty() {
+            decompressor
+                .as_mut()
+                .unwrap()
+                .get_mut()
+                .scanner
+                .end_stream();
+        }
+        if count == 0 && !self.single_stream && has_magic(self.get_mut().peek(4)?) {
             // We reached the end of the stream, but there's another one.
             // Recreate the decompressor and try again.
-            self.decompressor = Some(BzDecoder::new(
-                self.decompressor.take().unwrap().into_inner(),
-            ));
-            self.read(out)
+            if let Decoder::Serial(d) = &mut self.decoder {
+                let mut source = d.take().unwrap().into_inner();
+                source.scanner.start_stream(source.source.peek(4)?);
+                *d = Some(StreamDecoder::new(source, self.small));
+            }
+            self.decompress(out)
         } else {
             Ok(count)
         }
     }
+
+    /// Buffer the next block from the parallel decoder.  Return false at
+    /// the end of the compressed data.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let (source, decoder) = match &mut self.decoder {
+            Decoder::Parallel(source, decoder) => (source, decoder),
+            Decoder::Serial(_) => unreachable!(),
+        };
+        match decoder.next_block(source)? {
+            Some(block) => {
+                self.buf.put(block);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl<R: BufRead> Read for Bzip2Reader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            return Ok(self.buf.read(out));
+        }
+        self.decompress(out)
+    }
+}
+
+impl<R: BufRead> BufRead for Bzip2Reader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buf.is_empty() && matches!(self.decoder, Decoder::Parallel(..)) {
+            // hand out decoded blocks directly
+            self.next_block()?;
+        } else if self.buf.is_empty() {
+            let mut buf = self.buf.take(OUTPUT_BUF_SIZE);
+            let ret = read_into(&mut buf, |out| self.decompress(out));
+            self.buf.put(buf);
+            ret?;
+        }
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+/// Decoder for a single bzip2 stream.  Like bzip2::bufread::BzDecoder,
+/// but with a choice of libbzip2's small decompression mode.
+pub(crate) struct StreamDecoder<R: BufRead> {
+    source: R,
+    decompressor: Decompress,
+    done: bool,
+}
+
+impl<R: BufRead> StreamDecoder<R> {
+    pub(crate) fn new(source: R, small: bool) -> Self {
+        Self {
+            source,
+            decompressor: Decompress::new(small),
+            done: false,
+        }
+    }
+
+    fn get_ref(&self) -> &R {
+        &self.source
+    }
+
+    fn get_mut(&mut self) -> &mut R {
+        &mut self.source
+    }
+
+    fn into_inner(self) -> R {
+        self.source
+    }
+}
+
+impl<R: BufRead> Read for StreamDecoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while !self.done {
+            let input = self.source.fill_buf()?;
+            let eof = input.is_empty();
+            let before_in = self.decompressor.total_in();
+            let before_out = self.decompressor.total_out();
+            let status = self.decompressor.decompress(input, out);
+            let consumed = (self.decompressor.total_in() - before_in) as usize;
+            let count = (self.decompressor.total_out() - before_out) as usize;
+            self.source.consume(consumed);
+            match status.map_err(|e| Error::new(ErrorKind::InvalidData, e))? {
+                Status::StreamEnd => self.done = true,
+                _ if eof && count == 0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "premature EOF reading bzip2 stream",
+                    ))
+                }
+                _ => (),
+            }
+            if count > 0 || out.is_empty() {
+                return Ok(count);
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// The serial decoder's source.  libbzip2 doesn't report block
+/// boundaries, so we scan the input it consumes if asked to.
+struct Source<R: BufRead> {
+    source: PeekReader<R>,
+    scanner: Scanner,
+    // collect block CRCs
+    scan: bool,
+}
+
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let count = buf.len().min(out.len());
+        out[..count].copy_from_slice(&buf[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<R: BufRead> BufRead for Source<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.source.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.scan {
+            // returns the same data as the caller's fill_buf() without
+            // reading
+            if let Ok(buf) = self.source.fill_buf() {
+                self.scanner.scan(&buf[..amt.min(buf.len())]);
+            }
+        }
+        self.source.consume(amt)
+    }
+}
+
+/// Scanner for the block and end-of-stream magic numbers, which aren't
+/// byte aligned.
+#[derive(Default)]
+struct Scanner {
+    stream: Option<Bzip2Stream>,
+    streams: u64,
+    // the most recent input bits
+    window: u64,
+    // a magic number we've found, and the number of input bits after it
+    pending: Option<(u64, usize)>,
+}
+
+impl Scanner {
+    fn start_stream(&mut self, header: &[u8]) {
+        if !has_magic(header) {
+            return;
+        }
+        self.stream = Some(Bzip2Stream::new(self.streams, header));
+        self.streams += 1;
+        self.window = 0;
+        self.pending = None;
+    }
+
+    fn end_stream(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.repair();
+        }
+    }
+
+    fn scan(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.window = self.window << 8 | u64::from(byte);
+            if let Some((magic, bits)) = self.pending {
+                if bits + 8 < CRC_BITS {
+                    self.pending = Some((magic, bits + 8));
+                    continue;
+                }
+                let crc = (self.window >> (bits + 8 - CRC_BITS)) as u32;
+                self.pending = None;
+                if let Some(stream) = self.stream.as_mut() {
+                    if magic == BLOCK_MAGIC {
+                        stream.block_crcs.push(crc);
+                        // an earlier end-of-stream magic was a false match
+                        stream.combined_crc = None;
+                    } else {
+                        stream.combined_crc = Some(crc);
+                    }
+                }
+                continue;
+            }
+            for bits in 0..8 {
+                let magic = self.window >> bits & ((1 << MAGIC_BITS) - 1);
+                if magic == BLOCK_MAGIC || magic == EOS_MAGIC {
+                    self.pending = Some((magic, bits));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct Bzip2Writer<W: Write> {
+    sink: W,
+    decompressor: Decompress,
+    buf: Vec<u8>,
+    // after the end of a stream, the bytes we've seen of the next one
+    magic: Option<Vec<u8>>,
+    // libbzip2 small decompression mode
+    small: bool,
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for Bzip2Writer<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bzip2Writer")
+            .field("sink", &self.sink)
+            .field("magic", &self.magic)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> Bzip2Writer<W> {
+    pub(crate) fn new(sink: W, config: &DecompressBuilder) -> Self {
+        Self {
+            sink,
+            decompressor: Decompress::new(config.bzip2_small),
+            buf: Vec::with_capacity(32 * 1024),
+            magic: None,
+            small: config.bzip2_small,
+        }
+    }
+
+    fn decompress(&mut self, input: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        loop {
+            self.buf.clear();
+            let before = self.decompressor.total_in();
+            let status = self
+                .decompressor
+                .decompress_vec(&input[consumed..], &mut self.buf)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            consumed += (self.decompressor.total_in() - before) as usize;
+            self.sink.write_all(&self.buf)?;
+            if status == Status::StreamEnd {
+                self.magic = Some(Vec::with_capacity(4));
+                return Ok(consumed);
+            }
+            if consumed == input.len() && self.buf.len() < self.buf.capacity() {
+                return Ok(consumed);
+            }
+        }
+    }
+}
+
+impl<W: Write> FormatWriter<W> for Bzip2Writer<W> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        if self.magic.is_none() {
+            let count = self.decompress(input)?;
+            if count > 0 || self.magic.is_none() {
+                return Ok(count);
+            }
+        }
+        let magic = self.magic.as_mut().unwrap();
+        // We reached the end of a stream.  Check whether there's another
+        // one.
+        let count = input.len().min(4 - magic.len());
+        magic.extend(&input[..count]);
+        if magic.len() < 4 {
+            return Ok(count);
+        }
+        if !has_magic(magic) {
+            // end of compressed data
+            return Ok(0);
+        }
+        // Recreate the decompressor and feed it the header.
+        let magic = self.magic.take().unwrap();
+        self.decompressor = Decompress::new(self.small);
+        if self.decompress(&magic)? != magic.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "couldn't decompress bzip2 stream header",
+            ));
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        match self.magic {
+            Some(magic) => Ok((self.sink, !magic.is_empty())),
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "premature EOF reading bzip2 stream",
+            )),
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+}
+
+/// Compute the combined CRC of a stream from its block CRCs.
+fn combine_crcs(crcs: &[u32]) -> u32 {
+    crcs.iter().fold(0, |crc, block| crc.rotate_left(1) ^ block)
 }
 
-fn has_magic<R: BufRead>(source: &mut PeekReader<R>) -> io::Result<bool> {
-    let peek = source.peek(4)?;
-    Ok(peek.len() == 4 && &peek[0..3] == b"BZh" && peek[3] >= b'1' && peek[3] <= b'9')
+pub(crate) fn has_magic(peek: &[u8]) -> bool {
+    peek.len() == 4 && &peek[0..3] == b"BZh" && peek[3] >= b'1' && peek[3] <= b'9'
 }
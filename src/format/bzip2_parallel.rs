@@ -0,0 +1,412 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Parallel bzip2 decoding.  bzip2 blocks are independent but aren't byte
+// aligned, and the format has no index, so we scan the input bit by bit
+// for the 48-bit block and end-of-stream magic numbers.  Each block is
+// repackaged as a single-block bzip2 stream and decoded by libbzip2 on a
+// worker thread, which verifies the block CRC.  We verify the combined
+// stream CRC ourselves as blocks are returned in order.
+//
+// The block magic can occur by chance inside compressed data.  If a block
+// fails to decode, we assume it was split at a false boundary, merge it
+// with the following block, and try again.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Error, ErrorKind, Read};
+use std::sync::Arc;
+
+use crate::format::bzip2::{
+    has_magic, Bzip2Stream, StreamDecoder, BLOCK_MAGIC, CRC_BITS, EOS_MAGIC, MAGIC_BITS,
+};
+use crate::format::pool::{JobResult, WorkerPool};
+use crate::PeekReader;
+
+/// A bzip2 block, copied out of the input and aligned to a byte boundary.
+#[derive(Debug)]
+struct Block {
+    level: u8,
+    data: Vec<u8>,
+    bits: usize,
+    // decode in libbzip2's small mode
+    small: bool,
+}
+
+impl Block {
+    /// The block CRC stored after the block magic.
+    fn crc(&self) -> u32 {
+        match self.data.get(6..10) {
+            Some(crc) => u32::from_be_bytes(crc.try_into().unwrap()),
+            // not a valid block; decoding will fail
+            None => 0,
+        }
+    }
+
+    /// Join a block that ended at a false boundary with the next one.
+    fn merge(&self, next: &Block) -> Block {
+        let mut writer = BitWriter::with_capacity(self.data.len() + next.data.len());
+        writer.copy(&self.data, 0, self.bits);
+        writer.copy(&next.data, 0, next.bits);
+        Block {
+            level: self.level,
+            bits: self.bits + next.bits,
+            data: writer.finish(),
+            small: self.small,
+        }
+    }
+
+    /// Decode the block by wrapping it in a single-block stream.
+    fn decode(&self) -> io::Result<Vec<u8>> {
+        let mut writer = BitWriter::with_capacity(self.data.len() + 20);
+        for &b in b"BZh" {
+            writer.push(b.into(), 8);
+        }
+        writer.push(self.level.into(), 8);
+        writer.copy(&self.data, 0, self.bits);
+        writer.push(EOS_MAGIC, MAGIC_BITS);
+        // the combined CRC of a single-block stream is the block CRC
+        writer.push(self.crc().into(), CRC_BITS);
+        let stream = writer.finish();
+
+        let mut out = Vec::with_capacity(usize::from(self.level - b'0') * 100_000);
+        StreamDecoder::new(&stream[..], self.small).read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+enum Pending {
+    StartOfStream([u8; 4]),
+    Block(Arc<Block>, JobResult<Vec<u8>>),
+    EndOfStream(u32),
+    Error(Error),
+}
+
+/// Scanner state for the current bzip2 stream.
+struct Stream {
+    level: u8,
+    // input bytes starting with the one containing the current block
+    data: Vec<u8>,
+    // bit offset of the current block in data
+    start: usize,
+    // whether we've seen the first block or end-of-stream magic
+    started: bool,
+    // the most recent input bits
+    window: u64,
+    // bit offset of the end-of-stream magic in data
+    end: Option<usize>,
+}
+
+pub(crate) struct ParallelDecoder {
+    pool: WorkerPool<Arc<Block>, Vec<u8>>,
+    max_pending: usize,
+    pending: VecDeque<Pending>,
+    stream: Option<Stream>,
+    // combined CRC of the blocks returned so far from this stream
+    crc: u32,
+    // structure of the stream, as returned so far
+    info: Option<Bzip2Stream>,
+    streams: u64,
+    // stop scanning at the end of the first stream
+    single_stream: bool,
+    scanned_stream: bool,
+    // libbzip2 small decompression mode
+    small: bool,
+    // no more input to scan
+    done: bool,
+}
+
+impl ParallelDecoder {
+    pub(crate) fn new(threads: u32, single_stream: bool, small: bool) -> io::Result<Self> {
+        Ok(Self {
+            pool: WorkerPool::new("bzip2 decoder", threads, |block: Arc<Block>| block.decode())?,
+            // keep the workers busy while we wait for the oldest block
+            max_pending: 2 * threads as usize,
+            pending: VecDeque::new(),
+            stream: None,
+            crc: 0,
+            info: None,
+            streams: 0,
+            single_stream,
+            scanned_stream: false,
+            small,
+            done: false,
+        })
+    }
+
+    /// Discard all state so the decoder can be used with a new source.
+    /// Blocks already queued are decoded, but the results are dropped.
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.stream = None;
+        self.crc = 0;
+        self.info = None;
+        self.streams = 0;
+        self.scanned_stream = false;
+        self.done = false;
+    }
+
+    pub(crate) fn stream(&self) -> Option<&Bzip2Stream> {
+        self.info.as_ref()
+    }
+
+    /// Return the next decoded block, or None at the end of the
+    /// compressed data.
+    pub(crate) fn next_block<R: BufRead>(
+        &mut self,
+        source: &mut PeekReader<R>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            while self.pending.len() < self.max_pending && !self.done {
+                self.scan(source);
+            }
+            let (mut block, result) = match self.pending.pop_front() {
+                Some(Pending::StartOfStream(header)) => {
+                    self.info = Some(Bzip2Stream::new(self.streams, &header));
+                    self.streams += 1;
+                    continue;
+                }
+                Some(Pending::Block(block, result)) => (block, result),
+                Some(Pending::EndOfStream(crc)) => {
+                    if crc != self.crc {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "bzip2 stream CRC mismatch",
+                        ));
+                    }
+                    self.crc = 0;
+                    if let Some(info) = self.info.as_mut() {
+                        info.combined_crc = Some(crc);
+                    }
+                    continue;
+                }
+                Some(Pending::Error(e)) => return Err(e),
+                None => return Ok(None),
+            };
+            let data = match result.wait() {
+                Ok(data) => data,
+                Err(e) => self.retry(source, &mut block).ok_or(e)?,
+            };
+            self.crc = self.crc.rotate_left(1) ^ block.crc();
+            if let Some(info) = self.info.as_mut() {
+                info.block_crcs.push(block.crc());
+            }
+            return Ok(Some(data));
+        }
+    }
+
+    /// After a block fails to decode, assume it ended at a false boundary
+    /// and try merging it with subsequent blocks.
+    fn retry<R: BufRead>(
+        &mut self,
+        source: &mut PeekReader<R>,
+        block: &mut Arc<Block>,
+    ) -> Option<Vec<u8>> {
+        while block.data.len() <= max_block_size(block.level) {
+            if self.pending.is_empty() && !self.done {
+                self.scan(source);
+            }
+            let next = match self.pending.front() {
+                Some(Pending::Block(next, _)) => next,
+                _ => return None,
+            };
+            *block = Arc::new(block.merge(next));
+            self.pending.pop_front();
+            if let Ok(data) = block.decode() {
+                return Some(data);
+            }
+        }
+        None
+    }
+
+    /// Scan input until we find the end of a block or a stream, and queue
+    /// the result.
+    fn scan<R: BufRead>(&mut self, source: &mut PeekReader<R>) {
+        if let Err(e) = self.try_scan(source) {
+            self.pending.push_back(Pending::Error(e));
+            self.done = true;
+        }
+    }
+
+    fn try_scan<R: BufRead>(&mut self, source: &mut PeekReader<R>) -> io::Result<()> {
+        if self.stream.is_none() {
+            let peek = source.peek(4)?;
+            if !has_magic(peek) || (self.single_stream && self.scanned_stream) {
+                // end of compressed data
+                self.done = true;
+                return Ok(());
+            }
+            self.pending
+                .push_back(Pending::StartOfStream(peek.try_into().unwrap()));
+            self.scanned_stream = true;
+            self.stream = Some(Stream {
+                level: peek[3],
+                data: Vec::new(),
+                start: 0,
+                started: false,
+                window: 0,
+                end: None,
+            });
+            source.consume(4);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        loop {
+            let buf = source.fill_buf()?;
+            if buf.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "premature EOF reading bzip2 stream",
+                ));
+            }
+            let mut count = 0;
+            let mut found_block = None;
+            let mut found_crc = None;
+            for &byte in buf {
+                count += 1;
+                stream.data.push(byte);
+                let len = stream.data.len() * 8;
+                if let Some(end) = stream.end {
+                    if len >= end + MAGIC_BITS + CRC_BITS {
+                        found_crc = Some(read_bits(&stream.data, end + MAGIC_BITS) as u32);
+                        break;
+                    }
+                    continue;
+                }
+                for bit in (0..8).rev() {
+                    stream.window = stream.window << 1 | u64::from(byte >> bit & 1);
+                    let magic = stream.window & ((1 << MAGIC_BITS) - 1);
+                    if magic != BLOCK_MAGIC && magic != EOS_MAGIC {
+                        continue;
+                    }
+                    let pos = match (len - bit).checked_sub(MAGIC_BITS) {
+                        Some(pos) => pos,
+                        None => continue,
+                    };
+                    if !stream.started {
+                        if pos != 0 {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                "invalid bzip2 block header",
+                            ));
+                        }
+                        stream.started = true;
+                    } else {
+                        found_block = Some((stream.start, pos));
+                    }
+                    stream.start = pos;
+                    if magic == EOS_MAGIC {
+                        stream.end = Some(pos);
+                    }
+                }
+                if found_block.is_some() {
+                    break;
+                }
+            }
+            source.consume(count);
+
+            if let Some((start, end)) = found_block {
+                let mut writer = BitWriter::with_capacity((end - start) / 8 + 1);
+                writer.copy(&stream.data, start, end);
+                let block = Arc::new(Block {
+                    level: stream.level,
+                    data: writer.finish(),
+                    bits: end - start,
+                    small: self.small,
+                });
+                let result = self.pool.submit(Arc::clone(&block))?;
+                self.pending.push_back(Pending::Block(block, result));
+            }
+            if let Some(crc) = found_crc {
+                self.pending.push_back(Pending::EndOfStream(crc));
+                self.stream = None;
+                return Ok(());
+            }
+
+            // discard input before the current block
+            let skip = stream.start / 8;
+            stream.data.drain(..skip);
+            stream.start -= skip * 8;
+            if let Some(end) = stream.end.as_mut() {
+                *end -= skip * 8;
+            }
+            if found_block.is_some() {
+                return Ok(());
+            }
+            if stream.end.is_none() && stream.data.len() > max_block_size(stream.level) {
+                return Err(Error::new(ErrorKind::InvalidData, "bzip2 block too large"));
+            }
+        }
+    }
+}
+
+/// Upper bound on the compressed size of a block.  A block holds up to
+/// 900 KB of symbols, each coded in at most 20 bits.
+fn max_block_size(level: u8) -> usize {
+    usize::from(level - b'0') * 100_000 * 5 / 2 + 1024
+}
+
+/// Read a big-endian 32-bit value at the specified bit offset.
+fn read_bits(data: &[u8], offset: usize) -> u64 {
+    let mut value = 0;
+    for i in offset..offset + 32 {
+        value = value << 1 | u64::from(data[i / 8] >> (7 - i % 8) & 1);
+    }
+    value
+}
+
+/// Big-endian bit writer.
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            out: Vec::with_capacity(capacity),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Append the low count bits of value.  count must be <= 56.
+    fn push(&mut self, value: u64, count: usize) {
+        self.acc = self.acc << count | (value & ((1 << count) - 1));
+        self.bits += count;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.out.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Append bits [start, end) of data.
+    fn copy(&mut self, data: &[u8], start: usize, end: usize) {
+        let mut pos = start;
+        while pos < end {
+            let offset = pos % 8;
+            let count = (8 - offset).min(end - pos);
+            let byte = data[pos / 8] >> (8 - offset - count);
+            self.push(byte.into(), count);
+            pos += count;
+        }
+    }
+
+    /// Pad to a byte boundary and return the output.
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.push(0, 8 - self.bits);
+        }
+        self.out
+    }
+}
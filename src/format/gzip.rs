@@ -13,39 +13,333 @@
 // limitations under the License.
 
 use flate2::bufread::GzDecoder;
-use std::io::{self, BufRead, Read};
+use std::io::{self, BufRead, Read, Write};
 
-use crate::{FormatReader, PeekReader, Result};
+use crate::format::{read_into, OutputBuffer, OUTPUT_BUF_SIZE};
+use crate::{FormatReader, FormatWriter, PeekReader, Result};
+
+/// Maximum length of the filename and comment, matching flate2.
+const MAX_HEADER_FIELD: usize = 65535;
+
+// header flags
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
 
 #[derive(Debug)]
 pub(crate) struct GzipReader<R: BufRead> {
-    decompressor: GzDecoder<PeekReader<R>>,
+    decompressor: GzDecoder<Source<R>>,
+    buf: OutputBuffer,
+    // None if the header is invalid; the decompressor will report it
+    header: Option<GzipHeader>,
+}
+
+/// Metadata from the header of a gzip member.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GzipHeader {
+    filename: Option<Vec<u8>>,
+    comment: Option<Vec<u8>>,
+    mtime: u32,
+    os: u8,
+    xfl: u8,
+    extra: Option<Vec<u8>>,
+}
+
+impl GzipHeader {
+    /// The original filename (FNAME), without the terminating NUL.  This
+    /// is conventionally ISO 8859-1.
+    pub fn filename(&self) -> Option<&[u8]> {
+        self.filename.as_deref()
+    }
+
+    /// The comment (FCOMMENT), without the terminating NUL.
+    pub fn comment(&self) -> Option<&[u8]> {
+        self.comment.as_deref()
+    }
+
+    /// The modification time of the original file (MTIME), in seconds
+    /// since the Unix epoch, or 0 if none was recorded.
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    /// The operating system that produced the member (OS).
+    pub fn os(&self) -> u8 {
+        self.os
+    }
+
+    /// Extra flags (XFL), indicating the compression level used.
+    pub fn xfl(&self) -> u8 {
+        self.xfl
+    }
+
+    /// The raw extra field (FEXTRA).
+    pub fn extra(&self) -> Option<&[u8]> {
+        self.extra.as_deref()
+    }
+
+    /// The subfields of the extra field, as pairs of a two-byte subfield
+    /// ID and data.  Stops early if the extra field is malformed.
+    pub fn extra_subfields(&self) -> impl Iterator<Item = ([u8; 2], &[u8])> {
+        let mut extra = self.extra().unwrap_or_default();
+        std::iter::from_fn(move || {
+            let header = extra.get(..4)?;
+            let len = usize::from(u16::from_le_bytes([header[2], header[3]]));
+            let data = extra.get(4..4 + len)?;
+            let id = [header[0], header[1]];
+            extra = &extra[4 + len..];
+            Some((id, data))
+        })
+    }
+}
+
+/// Idle decoder, kept for reuse with another source.
+pub(crate) struct GzipState<R: BufRead> {
+    decompressor: GzDecoder<Source<R>>,
+    buf: OutputBuffer,
 }
 
+/// The decompressor's source, which is removed while the decompressor is
+/// idle.
+#[derive(Debug)]
+struct Source<R: BufRead>(Option<PeekReader<R>>);
+
 impl<R: BufRead> GzipReader<R> {
     pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
         Ok(source.peek(2)? == b"\x1f\x8b")
     }
 
-    pub(crate) fn new(source: PeekReader<R>) -> Self {
-        Self {
-            decompressor: GzDecoder::new(source),
-        }
+    pub(crate) fn with_state(
+        mut source: PeekReader<R>,
+        state: Option<GzipState<R>>,
+    ) -> Result<Self> {
+        let header = parse_header(&mut source)?;
+        let source = Source(Some(source));
+        Ok(match state {
+            Some(mut state) => {
+                state.decompressor.reset(source);
+                Self {
+                    decompressor: state.decompressor,
+                    buf: state.buf,
+                    header,
+                }
+            }
+            None => Self {
+                decompressor: GzDecoder::new(source),
+                buf: OutputBuffer::default(),
+                header,
+            },
+        })
+    }
+
+    pub(crate) fn header(&self) -> Option<&GzipHeader> {
+        self.header.as_ref()
+    }
+
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, GzipState<R>) {
+        let source = self.decompressor.reset(Source(None));
+        self.buf.clear();
+        let state = GzipState {
+            decompressor: self.decompressor,
+            buf: self.buf,
+        };
+        (source.into_inner(), state)
     }
 }
 
 impl<R: BufRead> FormatReader<R> for GzipReader<R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        self.decompressor.get_ref().get_ref()
+    }
+
     fn get_mut(&mut self) -> &mut PeekReader<R> {
-        self.decompressor.get_mut()
+        self.decompressor.get_mut().get_mut()
+    }
+
+    fn into_inner(self) -> PeekReader<R> {
+        self.decompressor.into_inner().into_inner()
+    }
+
+    // we only decode one member
+    fn member(&self) -> u64 {
+        0
+    }
+}
+
+impl<R: BufRead> Source<R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        self.0
+            .as_ref()
+            .expect("idle gzip decompressor has no source")
+    }
+
+    fn get_mut(&mut self) -> &mut PeekReader<R> {
+        self.0
+            .as_mut()
+            .expect("idle gzip decompressor has no source")
     }
 
     fn into_inner(self) -> PeekReader<R> {
-        self.decompressor.into_inner()
+        self.0.expect("idle gzip decompressor has no source")
+    }
+}
+
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.get_mut().read(out)
+    }
+}
+
+impl<R: BufRead> BufRead for Source<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.get_mut().fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.get_mut().consume(amt)
     }
 }
 
 impl<R: BufRead> Read for GzipReader<R> {
     fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            return Ok(self.buf.read(out));
+        }
         self.decompressor.read(out)
     }
 }
+
+impl<R: BufRead> BufRead for GzipReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buf.is_empty() {
+            let mut buf = self.buf.take(OUTPUT_BUF_SIZE);
+            let ret = read_into(&mut buf, |out| self.decompressor.read(out));
+            self.buf.put(buf);
+            ret?;
+        }
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct GzipWriter<W: Write> {
+    decompressor: flate2::write::GzDecoder<W>,
+    // whether the decompressor has declined further input
+    done: bool,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub(crate) fn new(sink: W) -> Self {
+        Self {
+            decompressor: flate2::write::GzDecoder::new(sink),
+            done: false,
+        }
+    }
+}
+
+impl<W: Write> FormatWriter<W> for GzipWriter<W> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        // GzDecoder returns Ok(0) after the end of the member
+        let count = self.decompressor.write(input)?;
+        if count == 0 && !input.is_empty() {
+            self.done = true;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.decompressor.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        Ok((self.decompressor.finish()?, self.done))
+    }
+
+    fn get_ref(&self) -> &W {
+        self.decompressor.get_ref()
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        self.decompressor.get_mut()
+    }
+}
+
+/// Parse the member header at the start of the source without consuming
+/// it.  Return None if it's truncated or invalid.
+fn parse_header<R: BufRead>(source: &mut PeekReader<R>) -> io::Result<Option<GzipHeader>> {
+    let fixed = source.peek(10)?;
+    // deflate is the only defined compression method
+    if fixed.len() < 10 || fixed[2] != 8 {
+        return Ok(None);
+    }
+    let flags = fixed[3];
+    let mut header = GzipHeader {
+        mtime: u32::from_le_bytes(fixed[4..8].try_into().unwrap()),
+        xfl: fixed[8],
+        os: fixed[9],
+        ..Default::default()
+    };
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = match source.peek(pos + 2)?.get(pos..pos + 2) {
+            Some(len) => usize::from(u16::from_le_bytes(len.try_into().unwrap())),
+            None => return Ok(None),
+        };
+        pos += 2;
+        match source.peek(pos + len)?.get(pos..pos + len) {
+            Some(extra) => header.extra = Some(extra.to_vec()),
+            None => return Ok(None),
+        }
+        pos += len;
+    }
+    for (flag, field) in [
+        (FNAME, &mut header.filename),
+        (FCOMMENT, &mut header.comment),
+    ] {
+        if flags & flag != 0 {
+            match peek_string(source, pos)? {
+                Some(value) => {
+                    pos += value.len() + 1;
+                    *field = Some(value);
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+    if flags & FHCRC != 0 && source.peek(pos + 2)?.len() < pos + 2 {
+        return Ok(None);
+    }
+    Ok(Some(header))
+}
+
+/// Peek at a NUL-terminated string starting at pos, and return it without
+/// the NUL.  Return None if it's unterminated or too long.
+fn peek_string<R: BufRead>(source: &mut PeekReader<R>, pos: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut amt = 256;
+    loop {
+        let peek = source.peek(pos + amt)?;
+        if let Some(len) = peek.get(pos..).and_then(|s| s.iter().position(|b| *b == 0)) {
+            return Ok(Some(peek[pos..pos + len].to_vec()));
+        }
+        if peek.len() < pos + amt || amt > MAX_HEADER_FIELD {
+            return Ok(None);
+        }
+        amt = (amt * 2).min(MAX_HEADER_FIELD + 1);
+    }
+}
+
+/// Read the decompressed size from the trailer of the last gzip member.
+/// It's stored modulo 2^32 and doesn't include any earlier members.
+pub(super) fn size_hint(input: &[u8]) -> Option<u64> {
+    // header plus trailer
+    if input.len() < 18 {
+        return None;
+    }
+    let isize = &input[input.len() - 4..];
+    Some(u32::from_le_bytes(isize.try_into().unwrap()).into())
+}
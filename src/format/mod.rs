@@ -12,37 +12,362 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::vec::Vec;
+use bytes::{Bytes, BytesMut};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
 use enum_dispatch::enum_dispatch;
-use std::io::BufRead;
+#[cfg(feature = "std")]
+use std::io::Write;
 
-use crate::PeekReader;
+use crate::io::{self, BufRead, Read};
+use crate::{CompressionFormat, DecompressBuilder, DecompressError, Format, PeekReader, Result};
 
 pub(crate) mod uncompressed;
 
 pub(crate) use self::uncompressed::*;
 
-#[cfg(feature = "bzip2")]
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
 pub(crate) mod bzip2;
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+mod bzip2_parallel;
 #[cfg(feature = "gzip")]
 pub(crate) mod gzip;
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust", feature = "zstd"))]
+mod pool;
+#[cfg(all(feature = "std", any(feature = "xz-rust", feature = "zstd-rust")))]
+// unused outside tests if the C backends take precedence
+#[cfg_attr(
+    not(any(
+        all(feature = "xz-rust", not(feature = "xz")),
+        all(feature = "zstd-rust", not(feature = "zstd"))
+    )),
+    allow(dead_code)
+)]
+mod pull_writer;
 #[cfg(feature = "xz")]
 pub(crate) mod xz;
+#[cfg(any(feature = "xz", feature = "xz-rust"))]
+pub(crate) mod xz_common;
+#[cfg(all(feature = "std", any(feature = "xz", feature = "xz-rust")))]
+pub(crate) mod xz_list;
+// also built alongside xz, for comparison in tests
+#[cfg(feature = "xz-rust")]
+#[cfg_attr(feature = "xz", allow(dead_code))]
+pub(crate) mod xz_rust;
+#[cfg(xz_threaded)]
+mod xz_threaded;
 #[cfg(feature = "zstd")]
 pub(crate) mod zstd;
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+pub(crate) mod zstd_common;
+#[cfg(all(feature = "std", any(feature = "zstd", feature = "zstd-rust")))]
+pub(crate) mod zstd_list;
+#[cfg(feature = "zstd")]
+mod zstd_parallel;
+// also built alongside zstd, for comparison in tests
+#[cfg(feature = "zstd-rust")]
+#[cfg_attr(feature = "zstd", allow(dead_code))]
+pub(crate) mod zstd_rust;
 
-#[cfg(feature = "bzip2")]
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
 pub(crate) use self::bzip2::*;
 #[cfg(feature = "gzip")]
 pub(crate) use self::gzip::*;
 #[cfg(feature = "xz")]
 pub(crate) use self::xz::*;
+#[cfg(all(feature = "xz-rust", not(feature = "xz")))]
+pub(crate) use self::xz_rust::*;
 #[cfg(feature = "zstd")]
 pub(crate) use self::zstd::*;
+#[cfg(all(feature = "zstd-rust", not(feature = "zstd")))]
+pub(crate) use self::zstd_rust::*;
 
 #[enum_dispatch(Format<R>)]
 // We'd like Read as a supertrait but enum_dispatch doesn't support it
 // https://gitlab.com/antonok/enum_dispatch/-/issues/56
 pub(crate) trait FormatReader<R: BufRead> {
+    fn get_ref(&self) -> &PeekReader<R>;
     fn get_mut(&mut self) -> &mut PeekReader<R>;
     fn into_inner(self) -> PeekReader<R>;
+    /// Index of the member being decoded, from 0.
+    fn member(&self) -> u64;
+}
+
+/// Size of the output buffer for formats whose decoder doesn't have a
+/// natural one.
+pub(crate) const OUTPUT_BUF_SIZE: usize = 32 * 1024;
+
+/// Size of the allocations that ChunkBuffer splits chunks from.
+const CHUNK_BUF_SIZE: usize = 8 * OUTPUT_BUF_SIZE;
+
+/// Buffer for reading chunks from readers that don't buffer their output.
+/// Chunks are split off the front, and the rest stays initialized for the
+/// next read, so chunks share an allocation and only new allocations are
+/// zeroed.
+#[derive(Debug, Default)]
+pub(crate) struct ChunkBuffer(BytesMut);
+
+impl ChunkBuffer {
+    pub(crate) fn read(&mut self, reader: &mut impl Read) -> io::Result<Option<Bytes>> {
+        if self.0.len() < OUTPUT_BUF_SIZE {
+            // the old allocation is freed when its chunks are dropped
+            self.0 = BytesMut::zeroed(CHUNK_BUF_SIZE);
+        }
+        let count = reader.read(&mut self.0[..OUTPUT_BUF_SIZE])?;
+        if count == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.0.split_to(count).freeze()))
+    }
+}
+
+/// Decompressed data that has been produced but not yet returned to the
+/// caller.  Formats use this to implement BufRead.
+// parts are unused if some formats are compiled out
+#[allow(dead_code)]
+#[derive(Default)]
+pub(crate) struct OutputBuffer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl fmt::Debug for OutputBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OutputBuffer")
+            .field("len", &self.data().len())
+            .finish()
+    }
+}
+
+#[allow(dead_code)]
+impl OutputBuffer {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    pub(crate) fn consume(&mut self, amt: usize) {
+        assert!(amt <= self.buf.len() - self.pos);
+        self.pos += amt;
+    }
+
+    /// Copy buffered data into out and return the number of bytes copied.
+    pub(crate) fn read(&mut self, out: &mut [u8]) -> usize {
+        let count = self.data().len().min(out.len());
+        out[..count].copy_from_slice(&self.buf[self.pos..self.pos + count]);
+        self.pos += count;
+        count
+    }
+
+    /// Take the underlying Vec for refilling.  It's returned empty, with at
+    /// least the specified spare capacity, and should be returned with
+    /// put().
+    pub(crate) fn take(&mut self, capacity: usize) -> Vec<u8> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.clear();
+        buf.reserve(capacity);
+        self.pos = 0;
+        buf
+    }
+
+    pub(crate) fn put(&mut self, buf: Vec<u8>) {
+        self.buf = buf;
+        self.pos = 0;
+    }
+
+    /// Discard buffered data, keeping the allocation.
+    pub(crate) fn clear(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+    }
+
+    /// Hand out the buffered data without copying it.
+    pub(crate) fn split(&mut self) -> Bytes {
+        let ret = Bytes::from(mem::take(&mut self.buf)).slice(self.pos..);
+        self.pos = 0;
+        ret
+    }
+}
+
+/// Fill an empty Vec from a function that writes to an initialized slice,
+/// and return the number of bytes written.
+#[allow(dead_code)]
+pub(crate) fn read_into(
+    buf: &mut Vec<u8>,
+    read: impl FnOnce(&mut [u8]) -> io::Result<usize>,
+) -> io::Result<usize> {
+    buf.resize(buf.capacity(), 0);
+    let ret = read(buf);
+    buf.truncate(*ret.as_ref().unwrap_or(&0));
+    ret
+}
+
+#[cfg(feature = "std")]
+#[enum_dispatch(WriteFormat<W>)]
+pub(crate) trait FormatWriter<W: Write> {
+    /// Decompress some of the input into the sink and return the number
+    /// of bytes consumed.  Returns Ok(0) only if the input is non-empty
+    /// and the compressed data has already ended, meaning that the caller
+    /// has found trailing data.
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Finish decompression at EOF and return the sink, plus a flag
+    /// indicating whether trailing data was consumed after the end of the
+    /// compressed data.  Fails if the compressed data was truncated.
+    fn finish(self) -> io::Result<(W, bool)>;
+    fn get_ref(&self) -> &W;
+    fn get_mut(&mut self) -> &mut W;
+}
+
+/// Idle decoder state for each format, kept so DecompressReader::reset()
+/// can reuse it instead of allocating new decoders.
+pub(crate) struct DecoderCache<'a, R: BufRead> {
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    bzip2: Option<Bzip2State>,
+    #[cfg(feature = "gzip")]
+    gzip: Option<GzipState<R>>,
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    xz: Option<XzState>,
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    zstd: Option<ZstdState<'a>>,
+    // 'a and R are unused if formats are compiled out
+    phantom: PhantomData<(&'a (), R)>,
+}
+
+impl<R: BufRead> fmt::Debug for DecoderCache<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecoderCache").finish_non_exhaustive()
+    }
+}
+
+impl<'a, R: BufRead> DecoderCache<'a, R> {
+    pub(crate) fn new() -> Self {
+        Self {
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            bzip2: None,
+            #[cfg(feature = "gzip")]
+            gzip: None,
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            xz: None,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            zstd: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Create a reader for the specified format, reusing the cached
+    /// decoder state for that format if there is one.
+    // config is unused if all formats are compiled out
+    #[allow(unused_variables)]
+    pub(crate) fn take(
+        &mut self,
+        format: CompressionFormat,
+        source: PeekReader<R>,
+        config: &DecompressBuilder,
+    ) -> Result<Format<'a, R>> {
+        use CompressionFormat::*;
+        Ok(match format {
+            Uncompressed => UncompressedReader::new(source).into(),
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Bzip2 => Bzip2Reader::with_state(source, config, self.bzip2.take())?.into(),
+            #[cfg(feature = "gzip")]
+            Gzip => GzipReader::with_state(source, self.gzip.take())?.into(),
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            Xz => XzReader::with_state(source, config, self.xz.take())?.into(),
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Zstd => ZstdReader::with_state(source, config, self.zstd.take())?.into(),
+        })
+    }
+
+    /// Cache the reader's decoder state, replacing any previously cached
+    /// state for its format, and return the reader's source.
+    pub(crate) fn put(&mut self, reader: Format<'a, R>) -> PeekReader<R> {
+        match reader {
+            Format::Uncompressed(r) => r.into_inner(),
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Format::Bzip2(r) => {
+                let (source, state) = r.into_state();
+                self.bzip2 = Some(state);
+                source
+            }
+            #[cfg(feature = "gzip")]
+            Format::Gzip(r) => {
+                let (source, state) = r.into_state();
+                self.gzip = Some(state);
+                source
+            }
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            Format::Xz(r) => {
+                let (source, state) = r.into_state();
+                self.xz = Some(state);
+                source
+            }
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Format::Zstd(r) => {
+                let (source, state) = r.into_state();
+                self.zstd = state;
+                source
+            }
+        }
+    }
+}
+
+/// Get the decompressed size of an in-memory input from the format's
+/// metadata, if it has any.  The result may be inaccurate if the input is
+/// corrupt or has trailing data.
+pub(crate) fn size_hint(format: CompressionFormat, input: &[u8]) -> Option<u64> {
+    use CompressionFormat::*;
+    match format {
+        Uncompressed => Some(input.len() as u64),
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+        Bzip2 => None,
+        #[cfg(feature = "gzip")]
+        Gzip => gzip::size_hint(input),
+        #[cfg(any(feature = "xz", feature = "xz-rust"))]
+        Xz => xz_common::size_hint(input),
+        #[cfg(feature = "zstd")]
+        Zstd => zstd::size_hint(input),
+        #[cfg(all(feature = "zstd-rust", not(feature = "zstd")))]
+        Zstd => zstd_rust::size_hint(input),
+    }
+}
+
+/// Detect the format of the upcoming data in the source, considering only
+/// the formats enabled in the config.
+// source is unused if all formats are compiled out
+#[allow(unused_variables)]
+pub(crate) fn detect<R: BufRead>(
+    source: &mut PeekReader<R>,
+    config: &DecompressBuilder,
+) -> Result<CompressionFormat> {
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    if config.bzip2 && Bzip2Reader::detect(source)? {
+        return Ok(CompressionFormat::Bzip2);
+    }
+
+    #[cfg(feature = "gzip")]
+    if config.gzip && GzipReader::detect(source)? {
+        return Ok(CompressionFormat::Gzip);
+    }
+
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    if config.xz && XzReader::detect(source)? {
+        return Ok(CompressionFormat::Xz);
+    }
+
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    if config.zstd && ZstdReader::detect(source)? {
+        return Ok(CompressionFormat::Zstd);
+    }
+
+    if config.uncompressed {
+        return Ok(CompressionFormat::Uncompressed);
+    }
+
+    Err(DecompressError::UnrecognizedFormat)
 }
@@ -0,0 +1,71 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Worker threads for formats that can decode independent pieces of their
+// input in parallel.  Each job gets its own result channel, so the reader
+// can collect results in input order.
+
+use std::io::{self, Error, ErrorKind};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job<T, U> = (T, SyncSender<io::Result<U>>);
+
+pub(crate) struct WorkerPool<T, U> {
+    jobs: Sender<Job<T, U>>,
+}
+
+impl<T: Send + 'static, U: Send + 'static> WorkerPool<T, U> {
+    pub(crate) fn new(name: &str, threads: u32, work: fn(T) -> io::Result<U>) -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job<T, U>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            // workers exit when the pool is dropped
+            thread::Builder::new()
+                .name(name.into())
+                .spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    let (input, result) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // the reader may have gone away
+                    let _ = result.send(work(input));
+                })?;
+        }
+        Ok(Self { jobs })
+    }
+
+    /// Queue a job and return a handle to its eventual result.
+    pub(crate) fn submit(&self, input: T) -> io::Result<JobResult<U>> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.jobs
+            .send((input, sender))
+            .map_err(|_| Error::new(ErrorKind::Other, "decoder threads exited"))?;
+        Ok(JobResult(receiver))
+    }
+}
+
+pub(crate) struct JobResult<U>(Receiver<io::Result<U>>);
+
+impl<U> JobResult<U> {
+    /// Wait for the job to finish.
+    pub(crate) fn wait(self) -> io::Result<U> {
+        self.0
+            .recv()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::Other, "decoder thread failed")))
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// FormatWriter for pure-Rust backends whose decoders can only pull from a
+// Read.  The format's reader runs on its own thread, reading input from a
+// channel, and sends decompressed data back to be written to the sink on
+// the caller's thread.  Because input is consumed asynchronously, trailing
+// data is reported on a later write or at finish rather than right away.
+
+use std::fmt;
+use std::io::{self, BufRead, Error, ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread;
+
+use crate::format::OUTPUT_BUF_SIZE;
+use crate::{DecompressBuilder, FormatReader, FormatWriter, PeekReader, Result};
+
+/// Number of input buffers queued for the decoder thread.
+const INPUT_QUEUE_LEN: usize = 4;
+
+/// A reader that can be driven by PullWriter.
+pub(crate) trait PullFormat: Read + FormatReader<Input> + Send + Sized + 'static {
+    fn start(source: PeekReader<Input>, config: &DecompressBuilder) -> Result<Self>;
+}
+
+enum Message {
+    Data(Vec<u8>),
+    /// The compressed data has ended.  The flag indicates whether it was
+    /// followed by trailing data.
+    End(bool),
+}
+
+pub(crate) struct PullWriter<W: Write, T> {
+    sink: W,
+    input: SyncSender<Vec<u8>>,
+    output: Receiver<io::Result<Message>>,
+    // set when the decoder thread reaches the end of the compressed data
+    trailing: Option<bool>,
+    phantom: PhantomData<T>,
+}
+
+impl<W: Write + fmt::Debug, T> fmt::Debug for PullWriter<W, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PullWriter")
+            .field("sink", &self.sink)
+            .field("trailing", &self.trailing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write, T: PullFormat> PullWriter<W, T> {
+    pub(crate) fn new(sink: W, config: &DecompressBuilder) -> io::Result<Self> {
+        let (input, receiver) = mpsc::sync_channel(INPUT_QUEUE_LEN);
+        let reader = T::start(PeekReader::new(Input::new(receiver)), config)?;
+        let (sender, output) = mpsc::channel();
+        thread::Builder::new()
+            .name("decompress writer".into())
+            .spawn(move || decode(reader, sender))?;
+        Ok(Self {
+            sink,
+            input,
+            output,
+            trailing: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Write decompressed data to the sink.  If wait is true, block until
+    /// the decoder thread exits.
+    fn drain(&mut self, wait: bool) -> io::Result<()> {
+        loop {
+            let message = if wait {
+                self.output.recv().ok()
+            } else {
+                self.output.try_recv().ok()
+            };
+            match message.transpose()? {
+                Some(Message::Data(buf)) => self.sink.write_all(&buf)?,
+                Some(Message::End(trailing)) => self.trailing = Some(trailing),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<W: Write, T: PullFormat> FormatWriter<W> for PullWriter<W, T> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.drain(false)?;
+        if self.trailing == Some(true) {
+            return Ok(0);
+        }
+        if self.input.send(input.to_vec()).is_err() {
+            // the decoder thread failed; find out why
+            self.drain(true)?;
+            return Err(thread_exited());
+        }
+        Ok(input.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain(false)?;
+        self.sink.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        let Self {
+            mut sink,
+            input,
+            output,
+            mut trailing,
+            ..
+        } = self;
+        // signal EOF
+        drop(input);
+        for message in output {
+            match message? {
+                Message::Data(buf) => sink.write_all(&buf)?,
+                Message::End(flag) => trailing = Some(flag),
+            }
+        }
+        match trailing {
+            Some(trailing) => Ok((sink, trailing)),
+            None => Err(thread_exited()),
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+}
+
+/// Decoder thread.  Decompress the input, then report whether there's
+/// trailing data and discard the rest of the input.
+fn decode<T: PullFormat>(mut reader: T, output: Sender<io::Result<Message>>) {
+    let result = (|| loop {
+        let mut buf = vec![0; OUTPUT_BUF_SIZE];
+        let count = reader.read(&mut buf)?;
+        if count == 0 {
+            return Ok(!reader.get_mut().peek(1)?.is_empty());
+        }
+        buf.truncate(count);
+        if output.send(Ok(Message::Data(buf))).is_err() {
+            // the writer is gone
+            return Ok(false);
+        }
+    })();
+    let failed = result.is_err();
+    // the writer may be gone
+    let _ = output.send(result.map(Message::End));
+    if !failed {
+        // keep accepting input until the writer finishes
+        let _ = io::copy(reader.get_mut(), &mut io::sink());
+    }
+}
+
+fn thread_exited() -> Error {
+    Error::new(ErrorKind::Other, "decoder thread exited")
+}
+
+/// Input received from PullWriter.
+pub(crate) struct Input {
+    receiver: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Input {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self {
+            receiver,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let count = buf.len().min(out.len());
+        out[..count].copy_from_slice(&buf[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.buf.len() {
+            match self.receiver.recv() {
+                Ok(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                // the writer has finished
+                Err(_) => break,
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
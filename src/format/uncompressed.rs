@@ -12,9 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{self, BufRead, Read};
-use std::marker::PhantomData;
+use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use std::io::Write;
 
+use crate::io::{self, BufRead, Read};
+#[cfg(feature = "std")]
+use crate::FormatWriter;
 use crate::{FormatReader, PeekReader};
 
 #[derive(Debug)]
@@ -24,7 +28,7 @@ pub(crate) struct UncompressedReader<'a, R: BufRead> {
     // which point we'd get a compile error on ReaderKind.  We don't want
     // to add an unused ReaderKind variant just to avoid this, and
     // UncompressedReader is always compiled in, so add a lifetime here.
-    phantom: PhantomData<&'a R>,
+    phantom: PhantomData<&'a ()>,
 }
 
 impl<R: BufRead> UncompressedReader<'_, R> {
@@ -37,6 +41,10 @@ impl<R: BufRead> UncompressedReader<'_, R> {
 }
 
 impl<R: BufRead> FormatReader<R> for UncompressedReader<'_, R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        &self.source
+    }
+
     fn get_mut(&mut self) -> &mut PeekReader<R> {
         &mut self.source
     }
@@ -44,6 +52,10 @@ impl<R: BufRead> FormatReader<R> for UncompressedReader<'_, R> {
     fn into_inner(self) -> PeekReader<R> {
         self.source
     }
+
+    fn member(&self) -> u64 {
+        0
+    }
 }
 
 impl<R: BufRead> Read for UncompressedReader<'_, R> {
@@ -51,3 +63,55 @@ impl<R: BufRead> Read for UncompressedReader<'_, R> {
         self.source.read(out)
     }
 }
+
+impl<R: BufRead> BufRead for UncompressedReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.source.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.source.consume(amt)
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub(crate) struct UncompressedWriter<'a, W: Write> {
+    sink: W,
+    // see UncompressedReader
+    phantom: PhantomData<&'a ()>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> UncompressedWriter<'_, W> {
+    pub(crate) fn new(sink: W) -> Self {
+        Self {
+            sink,
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> FormatWriter<W> for UncompressedWriter<'_, W> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        self.sink.write_all(input)?;
+        Ok(input.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        Ok((self.sink, false))
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+}
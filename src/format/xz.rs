@@ -13,48 +13,110 @@
 // limitations under the License.
 
 // Implementation of an API similar to xz2::bufread::XzDecoder using
-// xz2::write::XzDecoder.  We need this because bufread::XzDecoder returns
+// xz2::stream::Stream.  We need this because bufread::XzDecoder returns
 // io::ErrorKind::InvalidData if there's trailing data after an xz stream
-// (which can't be disambiguated from an actual error) but write::XzDecoder
-// returns Ok(0).  Return Ok(0) in this case and allow the caller to decide
-// what it wants to do about trailing data.
-//
-// https://github.com/alexcrichton/xz2-rs/pull/86
+// (which can't be disambiguated from an actual error).  Driving the Stream
+// directly lets us decode straight into the caller's buffer, and stop at
+// the end of each stream to check for Stream Padding and another
+// concatenated stream.  If there isn't one, return Ok(0) and allow the
+// caller to decide what it wants to do about trailing data.  With the
+// xz-threaded feature and liblzma >= 5.4, XzReader can use liblzma's
+// multithreaded decoder instead.
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::Bytes;
 use std::fmt;
-use std::io::{self, BufRead, Read, Write};
-use xz2::write::XzDecoder;
+use std::io::{self, BufRead, Error, ErrorKind, Read, Write};
+use xz2::stream::{self, Action, Status, Stream};
 
-use crate::{FormatReader, PeekReader, Result};
+use crate::format::xz_common::{self, has_magic, MAGIC};
+#[cfg(xz_threaded)]
+use crate::format::xz_threaded::ThreadedStream;
+use crate::format::{OutputBuffer, OUTPUT_BUF_SIZE};
+use crate::{DecompressBuilder, DecompressError, FormatReader, FormatWriter, PeekReader, Result};
 
 pub(crate) struct XzReader<R: BufRead> {
     source: PeekReader<R>,
-    decompressor: XzDecoder<bytes::buf::Writer<BytesMut>>,
+    decoder: Decoder,
+    // only used for BufRead
+    buf: OutputBuffer,
+    // we've reached the end of an xz stream and need to look for another
+    end_of_stream: bool,
+    // index of the current stream
+    stream: u64,
+    config: DecompressBuilder,
+}
+
+/// Idle reader state, kept for reuse with another source.  liblzma
+/// decoders can't be reinitialized through xz2, so only the output buffer
+/// is reused.
+pub(crate) struct XzState {
+    buf: OutputBuffer,
 }
 
 impl<R: BufRead + fmt::Debug> fmt::Debug for XzReader<R> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("XzReader")
             .field("source", &self.source)
+            .field("buf", &self.buf)
+            .field("end_of_stream", &self.end_of_stream)
             .finish_non_exhaustive()
     }
 }
 
 impl<R: BufRead> XzReader<R> {
     pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
-        Ok(source.peek(6)? == b"\xfd7zXZ\x00")
+        Ok(has_magic(source.peek(6)?))
     }
 
-    pub(crate) fn new(source: PeekReader<R>) -> Self {
-        Self {
+    pub(crate) fn with_state(
+        source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<XzState>,
+    ) -> Result<Self> {
+        Ok(Self {
             source,
-            decompressor: XzDecoder::new(BytesMut::new().writer()),
+            decoder: Decoder::new(config)?,
+            buf: state.map(|state| state.buf).unwrap_or_default(),
+            end_of_stream: false,
+            stream: 0,
+            config: config.clone(),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_threaded(&self) -> bool {
+        #[cfg(xz_threaded)]
+        if let Decoder::Threaded(_) = self.decoder {
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, XzState) {
+        self.buf.clear();
+        (self.source, XzState { buf: self.buf })
+    }
+
+    /// Skip Stream Padding after the end of a stream, and start decoding
+    /// the next stream if there is one.  Return false if there isn't.
+    fn next_stream(&mut self) -> io::Result<bool> {
+        if !xz_common::next_stream(&mut self.source, self.config.trailing_data)?
+            || self.config.single_member
+        {
+            return Ok(false);
         }
+        self.decoder = Decoder::new(&self.config)?;
+        self.end_of_stream = false;
+        self.stream += 1;
+        Ok(true)
     }
 }
 
 impl<R: BufRead> FormatReader<R> for XzReader<R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        &self.source
+    }
+
     fn get_mut(&mut self) -> &mut PeekReader<R> {
         &mut self.source
     }
@@ -62,35 +124,332 @@ impl<R: BufRead> FormatReader<R> for XzReader<R> {
     fn into_inner(self) -> PeekReader<R> {
         self.source
     }
+
+    fn member(&self) -> u64 {
+        self.stream
+    }
 }
 
-impl<R: BufRead> Read for XzReader<R> {
-    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+impl<R: BufRead> XzReader<R> {
+    /// Decompress into the caller's buffer.
+    fn decompress(&mut self, out: &mut [u8]) -> io::Result<usize> {
         if out.is_empty() {
             return Ok(0);
         }
+        self.run(|decoder, input, action| decoder.process(input, out, action))
+    }
+
+    /// Decompress into the output buffer's spare capacity, which doesn't
+    /// need to be zeroed first.
+    fn fill(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            let mut buf = self.buf.take(OUTPUT_BUF_SIZE);
+            let ret =
+                self.run(|decoder, input, action| decoder.process_vec(input, &mut buf, action));
+            self.buf.put(buf);
+            ret?;
+        }
+        Ok(())
+    }
+
+    /// Run the decoder until it produces output or we reach the end of
+    /// the compressed data, and return the amount of output.
+    fn run(
+        &mut self,
+        mut process: impl FnMut(
+            &mut Decoder,
+            &[u8],
+            Action,
+        ) -> std::result::Result<Status, stream::Error>,
+    ) -> io::Result<usize> {
         loop {
-            let buf = self.decompressor.get_mut().get_mut();
-            if !buf.is_empty() {
-                let count = buf.len().min(out.len());
-                buf.copy_to_slice(&mut out[..count]);
-                return Ok(count);
+            if self.end_of_stream && !self.next_stream()? {
+                return Ok(0);
             }
             let in_ = self.source.fill_buf()?;
-            if in_.is_empty() {
-                // EOF
-                self.decompressor.finish()?;
-                return Ok(0);
+            let eof = in_.is_empty();
+            let action = if eof { Action::Finish } else { Action::Run };
+            let in_before = self.decoder.total_in();
+            let out_before = self.decoder.total_out();
+            let status = process(&mut self.decoder, in_, action)
+                .map_err(|e| convert_error(e, self.config.xz_memlimit))?;
+            let consumed = (self.decoder.total_in() - in_before) as usize;
+            let produced = (self.decoder.total_out() - out_before) as usize;
+            self.source.consume(consumed);
+            if let Status::StreamEnd = status {
+                self.end_of_stream = true;
+            } else if consumed == 0 && produced == 0 {
+                // the decoder can't make progress
+                if eof {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "premature EOF reading xz stream",
+                    ));
+                }
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "xz decoder made no progress",
+                ));
             }
-            let count = self.decompressor.write(in_)?;
-            if count == 0 {
-                // end of compressed data
-                return Ok(0);
+            if produced > 0 {
+                return Ok(produced);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for XzReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            return Ok(self.buf.read(out));
+        }
+        self.decompress(out)
+    }
+}
+
+impl<R: BufRead> XzReader<R> {
+    /// Return the next chunk of decompressed data without copying it, or
+    /// None at the end of the compressed data.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        self.fill()?;
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.split()))
+    }
+}
+
+impl<R: BufRead> BufRead for XzReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill()?;
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+/// Single-threaded or multithreaded liblzma stream decoder.
+enum Decoder {
+    Single(Stream),
+    #[cfg(xz_threaded)]
+    Threaded(ThreadedStream),
+}
+
+impl Decoder {
+    fn new(config: &DecompressBuilder) -> io::Result<Self> {
+        #[cfg(xz_threaded)]
+        if config.xz_threads > 1 {
+            match ThreadedStream::new(
+                config.xz_threads,
+                config.xz_thread_memlimit,
+                config.xz_memlimit,
+            ) {
+                Ok(stream) => return Ok(Self::Threaded(stream)),
+                // liblzma can't decode with threads; fall back
+                Err(stream::Error::Options | stream::Error::UnsupportedCheck) => (),
+                Err(e) => return Err(e.into()),
             }
-            self.source.consume(count);
-            // decompressor normally wouldn't fill buf until the next
-            // write call
-            self.decompressor.flush()?;
         }
+        Ok(Self::Single(new_stream(config.xz_memlimit)?))
+    }
+
+    fn process(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        action: Action,
+    ) -> std::result::Result<Status, stream::Error> {
+        match self {
+            Self::Single(s) => s.process(input, output, action),
+            #[cfg(xz_threaded)]
+            Self::Threaded(s) => s.process(input, output, action),
+        }
+    }
+
+    fn process_vec(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        action: Action,
+    ) -> std::result::Result<Status, stream::Error> {
+        match self {
+            Self::Single(s) => s.process_vec(input, output, action),
+            #[cfg(xz_threaded)]
+            Self::Threaded(s) => s.process_vec(input, output, action),
+        }
+    }
+
+    fn total_in(&self) -> u64 {
+        match self {
+            Self::Single(s) => s.total_in(),
+            #[cfg(xz_threaded)]
+            Self::Threaded(s) => s.total_in(),
+        }
+    }
+
+    fn total_out(&self) -> u64 {
+        match self {
+            Self::Single(s) => s.total_out(),
+            #[cfg(xz_threaded)]
+            Self::Threaded(s) => s.total_out(),
+        }
+    }
+}
+
+pub(crate) struct XzWriter<W: Write> {
+    sink: W,
+    stream: Stream,
+    buf: Vec<u8>,
+    // after the end of a stream, what we've seen of the next one
+    boundary: Option<Boundary>,
+    trailing_data: bool,
+    memlimit: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Boundary {
+    padding: u64,
+    magic: Vec<u8>,
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for XzWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XzWriter")
+            .field("sink", &self.sink)
+            .field("boundary", &self.boundary)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> XzWriter<W> {
+    pub(crate) fn new(sink: W, config: &DecompressBuilder) -> io::Result<Self> {
+        Ok(Self {
+            sink,
+            stream: new_stream(config.xz_memlimit)?,
+            buf: Vec::with_capacity(32 * 1024),
+            boundary: None,
+            trailing_data: config.trailing_data,
+            memlimit: config.xz_memlimit,
+        })
+    }
+
+    fn decompress(&mut self, input: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        loop {
+            self.buf.clear();
+            let before = self.stream.total_in();
+            let status = self
+                .stream
+                .process_vec(&input[consumed..], &mut self.buf, Action::Run)
+                .map_err(|e| convert_error(e, self.memlimit))?;
+            consumed += (self.stream.total_in() - before) as usize;
+            self.sink.write_all(&self.buf)?;
+            if let Status::StreamEnd = status {
+                self.boundary = Some(Boundary::default());
+                return Ok(consumed);
+            }
+            if consumed == input.len() && self.buf.len() < self.buf.capacity() {
+                return Ok(consumed);
+            }
+        }
+    }
+}
+
+impl<W: Write> FormatWriter<W> for XzWriter<W> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        if self.boundary.is_none() {
+            let count = self.decompress(input)?;
+            if count > 0 || self.boundary.is_none() {
+                return Ok(count);
+            }
+        }
+        // We reached the end of a stream.  Skip Stream Padding and check
+        // for another stream.
+        let boundary = self.boundary.as_mut().unwrap();
+        let mut consumed = 0;
+        if boundary.magic.is_empty() {
+            while consumed < input.len() && input[consumed] == 0 {
+                consumed += 1;
+            }
+            boundary.padding += consumed as u64;
+            if consumed == input.len() {
+                return Ok(consumed);
+            }
+        }
+        let count = (input.len() - consumed).min(MAGIC.len() - boundary.magic.len());
+        boundary.magic.extend(&input[consumed..consumed + count]);
+        consumed += count;
+        if !MAGIC.starts_with(&boundary.magic) {
+            // end of compressed data
+            return Ok(0);
+        }
+        if boundary.magic.len() < MAGIC.len() {
+            return Ok(consumed);
+        }
+        if boundary.padding % 4 != 0 {
+            return Err(DecompressError::XzStreamPadding.into());
+        }
+        // Start the next stream and feed it the header.
+        let boundary = self.boundary.take().unwrap();
+        self.stream = new_stream(self.memlimit)?;
+        if self.decompress(&boundary.magic)? != boundary.magic.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "couldn't decompress xz stream header",
+            ));
+        }
+        Ok(consumed)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        let boundary = match self.boundary {
+            Some(boundary) => boundary,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "premature EOF reading xz stream",
+                ))
+            }
+        };
+        if !boundary.magic.is_empty() {
+            // partial magic number
+            return Ok((self.sink, true));
+        }
+        if boundary.padding % 4 != 0 {
+            if !self.trailing_data {
+                return Err(DecompressError::XzStreamPadding.into());
+            }
+            return Ok((self.sink, true));
+        }
+        Ok((self.sink, false))
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+}
+
+fn new_stream(memlimit: Option<u64>) -> io::Result<Stream> {
+    // we check for concatenated streams ourselves
+    Ok(Stream::new_stream_decoder(memlimit.unwrap_or(u64::MAX), 0)?)
+}
+
+/// Report hitting the configured memory limit as a typed error.
+fn convert_error(e: stream::Error, memlimit: Option<u64>) -> io::Error {
+    match (e, memlimit) {
+        (stream::Error::MemLimit, Some(limit)) => {
+            DecompressError::XzMemlimitExceeded { limit }.into()
+        }
+        (e, _) => e.into(),
     }
 }
@@ -0,0 +1,238 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Parts of the xz container format shared by the liblzma and pure-Rust
+// backends.
+
+#[cfg(not(feature = "std"))]
+use alloc::{format, vec::Vec};
+
+use crate::io::{self, BufRead, Error, ErrorKind};
+use crate::{DecompressError, PeekReader, Result};
+
+pub(crate) const MAGIC: &[u8] = b"\xfd7zXZ\x00";
+/// Size of the Stream Header and of the Stream Footer.
+pub(crate) const HEADER_SIZE: u64 = 12;
+
+pub(crate) fn has_magic(peek: &[u8]) -> bool {
+    peek.starts_with(MAGIC)
+}
+
+/// Skip Stream Padding after the end of a stream, and return whether
+/// another stream follows.
+pub(crate) fn next_stream<R: BufRead>(
+    source: &mut PeekReader<R>,
+    trailing_data: bool,
+) -> io::Result<bool> {
+    // Stream Padding is a multiple of 4 null bytes
+    while source.peek(4)? == b"\0\0\0\0" {
+        source.consume(4);
+    }
+    let peek = source.peek(3 + MAGIC.len())?;
+    let zeros = peek.iter().take_while(|b| **b == 0).count();
+    if zeros > 0 {
+        // Leftover null bytes are invalid padding if they're followed by
+        // another stream, or by EOF when trailing data isn't allowed.
+        // Otherwise they're the start of trailing data.
+        let rest = &peek[zeros..];
+        if (rest.is_empty() && !trailing_data) || has_magic(rest) {
+            return Err(DecompressError::XzStreamPadding.into());
+        }
+        return Ok(false);
+    }
+    // otherwise, end of compressed data
+    Ok(has_magic(peek))
+}
+
+/// Sum the uncompressed sizes recorded in the indexes of the xz streams,
+/// working backward from the end of the input.
+pub(crate) fn size_hint(mut input: &[u8]) -> Option<u64> {
+    let end = input.len() as u64;
+    uncompressed_size(&read_streams(&mut input, end).ok()?)
+}
+
+/// Total uncompressed size of the streams, or None if it overflows.
+pub(crate) fn uncompressed_size(streams: &[StreamIndex]) -> Option<u64> {
+    streams
+        .iter()
+        .flat_map(|stream| &stream.records)
+        .try_fold(0u64, |total, (_, size)| total.checked_add(*size))
+}
+
+/// Random access to an xz input, for walking its streams backward.
+pub(crate) trait ReadAt {
+    /// Read exactly len bytes at the specified offset.
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>>;
+}
+
+impl ReadAt for &[u8] {
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(len).ok())
+            .and_then(|(offset, len)| self.get(offset..offset.checked_add(len)?))
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                Error::new(ErrorKind::UnexpectedEof, "premature EOF reading xz stream").into()
+            })
+    }
+}
+
+/// A stream, as described by its footer and index.
+// only size_hint() is available without std
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) struct StreamIndex {
+    /// Offset of the Stream Header.
+    pub(crate) offset: u64,
+    /// Offset of the end of the Stream Footer.
+    pub(crate) end: u64,
+    pub(crate) flags: [u8; 2],
+    /// Size of the Stream Padding after the stream.
+    pub(crate) padding: u64,
+    /// Unpadded Size and Uncompressed Size of each block.
+    pub(crate) records: Vec<(u64, u64)>,
+}
+
+/// Read the streams of the input ending at the specified offset.  As with
+/// liblzma, we walk backward from the end, reading each stream's footer
+/// and index and then checking its header.
+pub(crate) fn read_streams<S: ReadAt>(source: &mut S, mut end: u64) -> Result<Vec<StreamIndex>> {
+    let mut streams = Vec::new();
+    while end > 0 {
+        // Stream Padding is a multiple of 4 null bytes
+        let mut padding = 0;
+        loop {
+            let start = end.checked_sub(4).ok_or_else(|| invalid("padding"))?;
+            if source.read_at(start, 4)? != [0; 4] {
+                break;
+            }
+            padding += 4;
+            end = start;
+        }
+        let stream = read_stream(source, end, padding)?;
+        end = stream.offset;
+        streams.push(stream);
+    }
+    streams.reverse();
+    Ok(streams)
+}
+
+/// Read the stream ending at the specified offset.
+fn read_stream<S: ReadAt>(source: &mut S, end: u64, padding: u64) -> Result<StreamIndex> {
+    // Stream Footer: CRC32, Backward Size, Stream Flags, magic
+    let footer_start = end
+        .checked_sub(HEADER_SIZE)
+        .ok_or_else(|| invalid("stream footer"))?;
+    let footer = source.read_at(footer_start, HEADER_SIZE)?;
+    if &footer[10..] != b"YZ" || !crc_matches(&footer[4..10], &footer[..4]) {
+        return Err(invalid("stream footer"));
+    }
+    let flags = [footer[8], footer[9]];
+    let index_size = (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+
+    // Index: Index Indicator, Number of Records, the Records, Index
+    // Padding, CRC32
+    let index_start = footer_start
+        .checked_sub(index_size)
+        .ok_or_else(|| invalid("index"))?;
+    let index = source.read_at(index_start, index_size)?;
+    let (index, crc) = index.split_at(index.len() - 4);
+    if index.first() != Some(&0) || !crc_matches(index, crc) {
+        return Err(invalid("index"));
+    }
+    let mut pos = 1;
+    let count = read_vli(index, &mut pos).ok_or_else(|| invalid("index"))?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let unpadded_size = read_vli(index, &mut pos).ok_or_else(|| invalid("index"))?;
+        let uncompressed_size = read_vli(index, &mut pos).ok_or_else(|| invalid("index"))?;
+        records.push((unpadded_size, uncompressed_size));
+    }
+    if index.len() - pos >= 4 || index[pos..].iter().any(|b| *b != 0) {
+        return Err(invalid("index"));
+    }
+    let blocks_size = records
+        .iter()
+        .try_fold(0u64, |total, (size, _)| total.checked_add(padded(*size)))
+        .ok_or_else(|| invalid("index"))?;
+
+    // Stream Header: magic, Stream Flags, CRC32
+    let offset = index_start
+        .checked_sub(blocks_size)
+        .and_then(|start| start.checked_sub(HEADER_SIZE))
+        .ok_or_else(|| invalid("index"))?;
+    let header = source.read_at(offset, HEADER_SIZE)?;
+    if !has_magic(&header) || !crc_matches(&header[6..8], &header[8..]) {
+        return Err(invalid("stream header"));
+    }
+    if header[6..8] != flags || flags[0] != 0 || flags[1] & 0xf0 != 0 {
+        return Err(invalid("stream flags"));
+    }
+    Ok(StreamIndex {
+        offset,
+        end,
+        flags,
+        padding,
+        records,
+    })
+}
+
+/// Round up to a multiple of 4, to account for Block Padding.
+pub(crate) fn padded(size: u64) -> u64 {
+    (size + 3) & !3
+}
+
+pub(crate) fn crc_matches(data: &[u8], crc: &[u8]) -> bool {
+    crc32(data).to_le_bytes() == crc
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, byte| {
+        (crc >> 8) ^ CRC32_TABLE[usize::from(crc as u8 ^ byte)]
+    })
+}
+
+pub(crate) fn invalid(what: &str) -> DecompressError {
+    Error::new(ErrorKind::InvalidData, format!("invalid xz {}", what)).into()
+}
+
+/// Decode a variable-length integer from the xz index.
+pub(crate) fn read_vli(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
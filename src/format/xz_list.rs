@@ -0,0 +1,269 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Listing the structure of an xz file without decompressing it, like
+// `xz --list`.  We walk the streams' footers and indexes with the parser
+// shared with size_hint(), and then read the block headers.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::format::xz_common::{
+    self, crc_matches, has_magic, invalid, padded, read_streams, read_vli, ReadAt, HEADER_SIZE,
+    MAGIC,
+};
+use crate::{DecompressError, Result};
+
+/// Structure of an xz file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct XzInfo {
+    pub streams: Vec<XzStream>,
+}
+
+impl XzInfo {
+    /// Total uncompressed size of all streams.
+    pub fn uncompressed_size(&self) -> u64 {
+        // xz_list() rejects indexes whose total overflows
+        self.streams
+            .iter()
+            .fold(0, |total, s| total.saturating_add(s.uncompressed_size))
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct XzStream {
+    /// Offset of the Stream Header from the start of the file.
+    pub offset: u64,
+    /// Size of the stream from the start of the Stream Header to the end
+    /// of the Stream Footer.
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub check: XzCheck,
+    /// Size of the Stream Padding after the stream.
+    pub padding: u64,
+    pub blocks: Vec<XzBlock>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct XzBlock {
+    /// Offset of the Block Header from the start of the file.
+    pub offset: u64,
+    /// Size of the block from the start of the Block Header to the end of
+    /// the check, excluding Block Padding.  This is the Unpadded Size
+    /// recorded in the index.
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    pub filters: Vec<XzFilter>,
+}
+
+/// Integrity check type of a stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum XzCheck {
+    None,
+    Crc32,
+    Crc64,
+    Sha256,
+    /// A check type reserved by the specification.
+    Other(u8),
+}
+
+impl XzCheck {
+    fn from_id(id: u8) -> Self {
+        match id {
+            0x00 => Self::None,
+            0x01 => Self::Crc32,
+            0x04 => Self::Crc64,
+            0x0a => Self::Sha256,
+            _ => Self::Other(id),
+        }
+    }
+}
+
+/// A filter in a block's filter chain.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct XzFilter {
+    pub id: u64,
+    pub properties: Vec<u8>,
+}
+
+impl XzFilter {
+    /// The filter's name, as used by `xz`, if the ID is a known one.
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self.id {
+            0x03 => "delta",
+            0x04 => "x86",
+            0x05 => "powerpc",
+            0x06 => "ia64",
+            0x07 => "arm",
+            0x08 => "armthumb",
+            0x09 => "sparc",
+            0x0a => "arm64",
+            0x0b => "riscv",
+            0x21 => "lzma2",
+            _ => return None,
+        })
+    }
+}
+
+/// List the streams and blocks of the xz file between the source's
+/// current position and its end, without decompressing it.  Offsets are
+/// relative to the starting position, which is restored afterward.  Fails
+/// with DecompressError::UnrecognizedFormat if the source isn't xz.
+pub fn xz_list<R: Read + Seek>(source: &mut R) -> Result<XzInfo> {
+    let start = source.stream_position()?;
+    let ret = Lister {
+        source,
+        start,
+        end: 0,
+    }
+    .list();
+    source.seek(SeekFrom::Start(start))?;
+    ret
+}
+
+/// Total uncompressed size recorded in the indexes of the xz file between
+/// the source's current position and its end, or None if it overflows.
+/// The starting position is restored afterward.
+pub(crate) fn xz_uncompressed_size<R: Read + Seek>(source: &mut R) -> Result<Option<u64>> {
+    let start = source.stream_position()?;
+    let mut lister = Lister {
+        source,
+        start,
+        end: 0,
+    };
+    let ret = lister
+        .open()
+        .and_then(|()| {
+            let end = lister.end;
+            read_streams(&mut lister, end)
+        })
+        .map(|streams| xz_common::uncompressed_size(&streams));
+    source.seek(SeekFrom::Start(start))?;
+    ret
+}
+
+struct Lister<'a, R: Read + Seek> {
+    source: &'a mut R,
+    start: u64,
+    // length of the input from start
+    end: u64,
+}
+
+impl<R: Read + Seek> Lister<'_, R> {
+    /// Check the magic number and find the end of the input.
+    fn open(&mut self) -> Result<()> {
+        let mut magic = [0; MAGIC.len()];
+        match self.source.read_exact(&mut magic) {
+            Ok(()) if has_magic(&magic) => {}
+            Ok(()) => return Err(DecompressError::UnrecognizedFormat),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(DecompressError::UnrecognizedFormat)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        self.end = self.source.seek(SeekFrom::End(0))? - self.start;
+        Ok(())
+    }
+
+    fn list(&mut self) -> Result<XzInfo> {
+        self.open()?;
+        let mut streams = Vec::new();
+        let mut total: u64 = 0;
+        for stream in read_streams(self, self.end)? {
+            let mut blocks = Vec::new();
+            let mut block_offset = stream.offset + HEADER_SIZE;
+            for (compressed_size, uncompressed_size) in stream.records {
+                blocks.push(XzBlock {
+                    offset: block_offset,
+                    compressed_size,
+                    uncompressed_size,
+                    filters: self.read_filters(block_offset)?,
+                });
+                block_offset += padded(compressed_size);
+            }
+            let uncompressed_size = blocks
+                .iter()
+                .try_fold(0u64, |size, b| size.checked_add(b.uncompressed_size))
+                .ok_or_else(|| invalid("index"))?;
+            total = total
+                .checked_add(uncompressed_size)
+                .ok_or_else(|| invalid("index"))?;
+            streams.push(XzStream {
+                offset: stream.offset,
+                compressed_size: stream.end - stream.offset,
+                uncompressed_size,
+                check: XzCheck::from_id(stream.flags[1]),
+                padding: stream.padding,
+                blocks,
+            });
+        }
+        Ok(XzInfo { streams })
+    }
+
+    /// Read the filter chain from the Block Header at the specified offset.
+    fn read_filters(&mut self, offset: u64) -> Result<Vec<XzFilter>> {
+        // Block Header Size, Block Flags, optional Compressed Size and
+        // Uncompressed Size, Filter Flags, Header Padding, CRC32
+        let size = self.read_at(offset, 1)?[0];
+        if size == 0 {
+            return Err(invalid("block header"));
+        }
+        let header = self.read_at(offset, (u64::from(size) + 1) * 4)?;
+        let (header, crc) = header.split_at(header.len() - 4);
+        let flags = header[1];
+        if flags & 0x3c != 0 || !crc_matches(header, crc) {
+            return Err(invalid("block header"));
+        }
+        let mut pos = 2;
+        for present in [flags & 0x40 != 0, flags & 0x80 != 0] {
+            if present {
+                read_vli(header, &mut pos).ok_or_else(|| invalid("block header"))?;
+            }
+        }
+        let mut filters = Vec::new();
+        for _ in 0..=(flags & 0x03) {
+            let id = read_vli(header, &mut pos).ok_or_else(|| invalid("block header"))?;
+            let properties = read_vli(header, &mut pos)
+                .and_then(|len| header.get(pos..pos.checked_add(len.try_into().ok()?)?))
+                .ok_or_else(|| invalid("block header"))?;
+            pos += properties.len();
+            filters.push(XzFilter {
+                id,
+                properties: properties.to_vec(),
+            });
+        }
+        Ok(filters)
+    }
+}
+
+impl<R: Read + Seek> ReadAt for Lister<'_, R> {
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        // don't trust lengths from the input with an allocation
+        if offset.checked_add(len).map_or(true, |end| end > self.end) {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "premature EOF reading xz stream",
+            )
+            .into());
+        }
+        self.source.seek(SeekFrom::Start(self.start + offset))?;
+        let mut buf = vec![0; len.try_into().map_err(|_| invalid("index"))?];
+        self.source.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
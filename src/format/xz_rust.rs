@@ -0,0 +1,238 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Pure-Rust xz backend using lzma-rust2.  Its XzReader only decodes one
+// stream at a time, and reads exactly the bytes of the stream, so we
+// handle Stream Padding and concatenated streams ourselves, as with
+// liblzma.  lzma-rust2 can only pull from a Read, so XzWriter runs the
+// reader on a thread.  Without std, lzma-rust2 has its own Read trait and
+// error type.
+
+use core::fmt;
+#[cfg(not(feature = "std"))]
+use lzma_rust2::Read as _;
+use lzma_rust2::XzReader as Decoder;
+
+#[cfg(feature = "std")]
+use crate::format::pull_writer::{Input, PullFormat, PullWriter};
+use crate::format::xz_common::{self, has_magic};
+use crate::format::{read_into, OutputBuffer, OUTPUT_BUF_SIZE};
+use crate::io::{self, BufRead, Read};
+use crate::{DecompressBuilder, FormatReader, PeekReader, Result};
+
+#[cfg(feature = "std")]
+pub(crate) type XzWriter<W> = PullWriter<W, XzReader<Input>>;
+
+pub(crate) struct XzReader<R: BufRead> {
+    // needs to be Option so we can replace the decoder
+    decoder: Option<Decoder<Source<R>>>,
+    // only used for BufRead
+    buf: OutputBuffer,
+    // we've reached the end of an xz stream and need to look for another
+    end_of_stream: bool,
+    // index of the current stream
+    stream: u64,
+    config: DecompressBuilder,
+}
+
+/// Idle reader state, kept for reuse with another source.
+pub(crate) struct XzState {
+    buf: OutputBuffer,
+}
+
+impl<R: BufRead + fmt::Debug> fmt::Debug for XzReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XzReader")
+            .field("source", &self.decoder.as_ref().unwrap().inner().source)
+            .field("buf", &self.buf)
+            .field("end_of_stream", &self.end_of_stream)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: BufRead> XzReader<R> {
+    pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
+        Ok(has_magic(source.peek(6)?))
+    }
+
+    pub(crate) fn with_state(
+        source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<XzState>,
+    ) -> Result<Self> {
+        Ok(Self {
+            decoder: Some(Decoder::new(Source::new(source), false)),
+            buf: state.map(|state| state.buf).unwrap_or_default(),
+            end_of_stream: false,
+            stream: 0,
+            config: config.clone(),
+        })
+    }
+
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, XzState) {
+        self.buf.clear();
+        let source = self.decoder.unwrap().into_inner().source;
+        (source, XzState { buf: self.buf })
+    }
+
+    /// Skip Stream Padding after the end of a stream, and start decoding
+    /// the next stream if there is one.  Return false if there isn't.
+    fn next_stream(&mut self) -> io::Result<bool> {
+        let trailing_data = self.config.trailing_data;
+        if !xz_common::next_stream(self.get_mut(), trailing_data)? || self.config.single_member {
+            return Ok(false);
+        }
+        let source = self.decoder.take().unwrap().into_inner();
+        self.decoder = Some(Decoder::new(source, false));
+        self.end_of_stream = false;
+        self.stream += 1;
+        Ok(true)
+    }
+
+    fn decompress(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.end_of_stream && !self.next_stream()? {
+                return Ok(0);
+            }
+            let ret = self.decoder.as_mut().unwrap().read(out);
+            #[cfg(not(feature = "std"))]
+            let ret = ret.map_err(|err| self.decoder_error(err));
+            let count = ret?;
+            if count > 0 {
+                return Ok(count);
+            }
+            self.end_of_stream = true;
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn decoder_error(&mut self, err: lzma_rust2::Error) -> io::Error {
+        use io::ErrorKind;
+        use lzma_rust2::Error::*;
+        if let Some(err) = self.decoder.as_mut().unwrap().inner_mut().error.take() {
+            return err;
+        }
+        match err {
+            Eof => ErrorKind::UnexpectedEof.into(),
+            Interrupted => ErrorKind::Interrupted.into(),
+            InvalidData(msg) => io::Error::new(ErrorKind::InvalidData, msg),
+            InvalidInput(msg) => io::Error::new(ErrorKind::InvalidInput, msg),
+            OutOfMemory(msg) => io::Error::new(ErrorKind::OutOfMemory, msg),
+            Unsupported(msg) => io::Error::new(ErrorKind::Unsupported, msg),
+            Other(msg) | WriteZero(msg) => io::Error::new(ErrorKind::Other, msg),
+        }
+    }
+}
+
+impl<R: BufRead> FormatReader<R> for XzReader<R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        &self.decoder.as_ref().unwrap().inner().source
+    }
+
+    fn get_mut(&mut self) -> &mut PeekReader<R> {
+        &mut self.decoder.as_mut().unwrap().inner_mut().source
+    }
+
+    fn into_inner(self) -> PeekReader<R> {
+        self.decoder.unwrap().into_inner().source
+    }
+
+    fn member(&self) -> u64 {
+        self.stream
+    }
+}
+
+impl<R: BufRead> Read for XzReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            return Ok(self.buf.read(out));
+        }
+        self.decompress(out)
+    }
+}
+
+impl<R: BufRead> BufRead for XzReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buf.is_empty() {
+            let mut buf = self.buf.take(OUTPUT_BUF_SIZE);
+            let ret = read_into(&mut buf, |out| self.decompress(out));
+            self.buf.put(buf);
+            ret?;
+        }
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+#[cfg(feature = "std")]
+impl PullFormat for XzReader<Input> {
+    fn start(source: PeekReader<Input>, config: &DecompressBuilder) -> Result<Self> {
+        Self::with_state(source, config, None)
+    }
+}
+
+/// The decoder's source.  lzma-rust2 expects short reads only at EOF, so
+/// fill the caller's buffer completely.  The decoder never asks for more
+/// than it needs, so this doesn't read past the end of the stream.
+struct Source<R: BufRead> {
+    source: PeekReader<R>,
+    // without std, source errors can't pass through lzma-rust2's error
+    // type, so we stash them here
+    #[cfg(not(feature = "std"))]
+    error: Option<io::Error>,
+}
+
+impl<R: BufRead> Source<R> {
+    fn new(source: PeekReader<R>) -> Self {
+        Self {
+            source,
+            #[cfg(not(feature = "std"))]
+            error: None,
+        }
+    }
+
+    fn read_full(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < out.len() {
+            match self.source.read(&mut out[count..])? {
+                0 => break,
+                n => count += n,
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: BufRead> Read for Source<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        self.read_full(out)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<R: BufRead> lzma_rust2::Read for Source<R> {
+    fn read(&mut self, out: &mut [u8]) -> lzma_rust2::Result<usize> {
+        self.read_full(out).map_err(|err| {
+            self.error = Some(err);
+            lzma_rust2::Error::Other("error reading xz source")
+        })
+    }
+}
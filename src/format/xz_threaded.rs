@@ -0,0 +1,181 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Minimal binding to liblzma's multithreaded stream decoder, which was
+// added in liblzma 5.4 and isn't wrapped by xz2 or lzma-sys.  The API
+// mirrors the parts of xz2::stream::Stream that XzReader uses.  build.rs
+// only enables it when linking against liblzma >= 5.4.
+
+use lzma_sys::{lzma_code, lzma_end, lzma_physmem, lzma_ret, lzma_stream};
+use std::mem;
+use std::os::raw::c_void;
+use xz2::stream::{Action, Error, Status};
+
+/// lzma_mt as of liblzma 5.4.  lzma-sys has the older layout, with the
+/// memory limits as reserved fields.
+#[repr(C)]
+struct MtOptions {
+    flags: u32,
+    threads: u32,
+    block_size: u64,
+    timeout: u32,
+    preset: u32,
+    filters: *const c_void,
+    check: u32,
+    reserved_enum1: u32,
+    reserved_enum2: u32,
+    reserved_enum3: u32,
+    reserved_int1: u32,
+    reserved_int2: u32,
+    reserved_int3: u32,
+    reserved_int4: u32,
+    memlimit_threading: u64,
+    memlimit_stop: u64,
+    reserved_int7: u64,
+    reserved_int8: u64,
+    reserved_ptr1: *mut c_void,
+    reserved_ptr2: *mut c_void,
+    reserved_ptr3: *mut c_void,
+    reserved_ptr4: *mut c_void,
+}
+
+// the layouts differ only in field names
+const _: () = assert!(mem::size_of::<MtOptions>() == mem::size_of::<lzma_sys::lzma_mt>());
+
+extern "C" {
+    fn lzma_stream_decoder_mt(strm: *mut lzma_stream, options: *const MtOptions) -> lzma_ret;
+}
+
+pub(crate) struct ThreadedStream {
+    raw: lzma_stream,
+}
+
+// lzma_stream holds raw pointers, but liblzma doesn't care which thread
+// calls into it
+unsafe impl Send for ThreadedStream {}
+
+impl ThreadedStream {
+    /// Create a decoder for a single xz stream.  If decoding with the
+    /// requested number of threads would use more than memlimit bytes,
+    /// liblzma reduces the thread count, down to single-threaded decoding.
+    /// If it would use more than memlimit_stop bytes even then, decoding
+    /// fails with Error::MemLimit.
+    pub(crate) fn new(
+        threads: u32,
+        memlimit: Option<u64>,
+        memlimit_stop: Option<u64>,
+    ) -> Result<Self, Error> {
+        let memlimit_stop = memlimit_stop.unwrap_or(u64::MAX);
+        // liblzma's recommended default is a quarter of physical memory
+        let memlimit = memlimit
+            .unwrap_or_else(|| unsafe { lzma_physmem() } / 4)
+            .min(memlimit_stop);
+        let options = MtOptions {
+            threads,
+            memlimit_threading: memlimit,
+            memlimit_stop,
+            // all-zero is a valid value for everything else
+            ..unsafe { mem::zeroed() }
+        };
+        let mut stream = Self {
+            raw: unsafe { mem::zeroed() },
+        };
+        cvt(unsafe { lzma_stream_decoder_mt(&mut stream.raw, &options) })?;
+        Ok(stream)
+    }
+
+    pub(crate) fn process(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        action: Action,
+    ) -> Result<Status, Error> {
+        self.code(input, output.as_mut_ptr(), output.len(), action)
+    }
+
+    /// Decompress into the spare capacity of output, like
+    /// xz2::stream::Stream::process_vec().
+    pub(crate) fn process_vec(
+        &mut self,
+        input: &[u8],
+        output: &mut Vec<u8>,
+        action: Action,
+    ) -> Result<Status, Error> {
+        let len = output.len();
+        let spare = output.spare_capacity_mut();
+        let before = self.raw.total_out;
+        let ret = self.code(input, spare.as_mut_ptr().cast(), spare.len(), action);
+        // liblzma initialized this many bytes of the spare capacity
+        unsafe { output.set_len(len + (self.raw.total_out - before) as usize) };
+        ret
+    }
+
+    fn code(
+        &mut self,
+        input: &[u8],
+        next_out: *mut u8,
+        avail_out: usize,
+        action: Action,
+    ) -> Result<Status, Error> {
+        self.raw.next_in = input.as_ptr();
+        self.raw.avail_in = input.len();
+        self.raw.next_out = next_out;
+        self.raw.avail_out = avail_out;
+        let action = match action {
+            Action::Run => lzma_sys::LZMA_RUN,
+            Action::SyncFlush => lzma_sys::LZMA_SYNC_FLUSH,
+            Action::FullFlush => lzma_sys::LZMA_FULL_FLUSH,
+            Action::FullBarrier => lzma_sys::LZMA_FULL_BARRIER,
+            Action::Finish => lzma_sys::LZMA_FINISH,
+        };
+        let ret = cvt(unsafe { lzma_code(&mut self.raw, action) });
+        // don't leave dangling pointers behind
+        self.raw.next_in = input.as_ptr();
+        self.raw.avail_in = 0;
+        self.raw.next_out = next_out;
+        self.raw.avail_out = 0;
+        ret
+    }
+
+    pub(crate) fn total_in(&self) -> u64 {
+        self.raw.total_in
+    }
+
+    pub(crate) fn total_out(&self) -> u64 {
+        self.raw.total_out
+    }
+}
+
+impl Drop for ThreadedStream {
+    fn drop(&mut self) {
+        unsafe { lzma_end(&mut self.raw) };
+    }
+}
+
+fn cvt(ret: lzma_ret) -> Result<Status, Error> {
+    match ret {
+        lzma_sys::LZMA_OK => Ok(Status::Ok),
+        lzma_sys::LZMA_STREAM_END => Ok(Status::StreamEnd),
+        lzma_sys::LZMA_GET_CHECK => Ok(Status::GetCheck),
+        lzma_sys::LZMA_BUF_ERROR => Ok(Status::MemNeeded),
+        lzma_sys::LZMA_NO_CHECK => Err(Error::NoCheck),
+        lzma_sys::LZMA_UNSUPPORTED_CHECK => Err(Error::UnsupportedCheck),
+        lzma_sys::LZMA_MEM_ERROR => Err(Error::Mem),
+        lzma_sys::LZMA_MEMLIMIT_ERROR => Err(Error::MemLimit),
+        lzma_sys::LZMA_FORMAT_ERROR => Err(Error::Format),
+        lzma_sys::LZMA_OPTIONS_ERROR => Err(Error::Options),
+        lzma_sys::LZMA_DATA_ERROR => Err(Error::Data),
+        _ => Err(Error::Program),
+    }
+}
@@ -23,19 +23,54 @@
 // done, return Ok(0) and allow the caller to decide what it wants to do
 // about trailing data.
 
-use bytes::{Buf, BytesMut};
+use bytes::Bytes;
 use std::fmt;
-use std::io::{self, BufRead, Error, ErrorKind, Read};
-use zstd::stream::raw::{Decoder, Operation};
-use zstd::zstd_safe::{MAGICNUMBER, MAGIC_SKIPPABLE_MASK, MAGIC_SKIPPABLE_START};
+use std::io::{self, BufRead, Error, ErrorKind, Read, Write};
+use zstd::stream::raw::{DParameter, Decoder, InBuffer, Operation, OutBuffer, WriteBuf};
+use zstd::zstd_safe::{
+    find_frame_compressed_size, get_frame_content_size, CONTENTSIZE_ERROR, CONTENTSIZE_UNKNOWN,
+};
 
-use crate::{FormatReader, PeekReader, Result};
+use crate::format::zstd_common::{
+    check_window, has_magic, is_skippable, peek_frame_header, read_skippable_frame,
+    SkippableHandler, ZstdFrameHeader,
+};
+use crate::format::zstd_parallel::{Frame, ParallelDecoder};
+use crate::format::OutputBuffer;
+use crate::{DecompressBuilder, FormatReader, FormatWriter, PeekReader, Result};
+
+/// Size of the internal output buffer.  BUFFER_SIZE is very large; use a
+/// smaller buffer to avoid wasting memory.  Reads at least this large are
+/// decompressed directly into the caller's buffer.
+const BUF_SIZE: usize = 16384;
+
+/// Longest possible frame header, including the magic number.
+const MAX_FRAME_HEADER_LEN: usize = 18;
 
 pub(crate) struct ZstdReader<'a, R: BufRead> {
     source: PeekReader<R>,
-    buf: BytesMut,
+    // the decoder writes directly into the uninitialized spare capacity
+    buf: OutputBuffer,
     decoder: Decoder<'a>,
     start_of_frame: bool,
+    frame_header: Option<ZstdFrameHeader>,
+    parallel: Option<ParallelDecoder>,
+    // stop at the end of the first frame
+    single_frame: bool,
+    // no more frames to decode
+    done: bool,
+    // frames started, including skippable frames
+    frames: u64,
+    // if set, we read skippable frames instead of the decoder
+    skippable: Option<SkippableHandler>,
+    window_log_max: Option<u32>,
+}
+
+/// Idle decoder state, kept for reuse with another source.
+pub(crate) struct ZstdState<'a> {
+    buf: OutputBuffer,
+    decoder: Decoder<'a>,
+    parallel: Option<ParallelDecoder>,
 }
 
 impl<'a, R: BufRead + fmt::Debug> fmt::Debug for ZstdReader<'a, R> {
@@ -48,22 +83,86 @@ impl<'a, R: BufRead + fmt::Debug> fmt::Debug for ZstdReader<'a, R> {
     }
 }
 
-impl<R: BufRead> ZstdReader<'_, R> {
+impl<'a, R: BufRead> ZstdReader<'a, R> {
     pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
-        Ok(has_magic(source)?)
+        Ok(has_magic(source.peek(4)?))
     }
 
-    pub(crate) fn new(source: PeekReader<R>) -> Result<Self> {
+    pub(crate) fn with_state(
+        mut source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<ZstdState<'a>>,
+    ) -> Result<Self> {
+        let frame_header = peek_frame_header(&mut source)?;
+        if let Some(state) = state {
+            return Ok(Self {
+                source,
+                buf: state.buf,
+                decoder: state.decoder,
+                start_of_frame: true,
+                frame_header,
+                parallel: state.parallel,
+                single_frame: config.single_member,
+                done: false,
+                frames: 0,
+                skippable: config.zstd_skippable.clone(),
+                window_log_max: config.zstd_window_log_max,
+            });
+        }
+        // frames are decoded in parallel with each other
+        let parallel = if config.zstd_threads > 1 && !config.single_member {
+            Some(ParallelDecoder::new(
+                config.zstd_threads,
+                config.zstd_skippable.is_some(),
+                config.zstd_window_log_max,
+            )?)
+        } else {
+            None
+        };
         Ok(Self {
             source,
-            buf: BytesMut::new(),
-            decoder: Decoder::new()?,
+            buf: OutputBuffer::default(),
+            decoder: new_decoder(config)?,
             start_of_frame: true,
+            frame_header,
+            parallel,
+            single_frame: config.single_member,
+            done: false,
+            frames: 0,
+            skippable: config.zstd_skippable.clone(),
+            window_log_max: config.zstd_window_log_max,
         })
     }
+
+    pub(crate) fn frame_header(&self) -> Option<&ZstdFrameHeader> {
+        self.frame_header.as_ref()
+    }
+
+    /// Return the source, plus the decoder state if it could be reset for
+    /// reuse.
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, Option<ZstdState<'a>>) {
+        // discard any partially decoded frame
+        if self.decoder.reinit().is_err() {
+            return (self.source, None);
+        }
+        self.buf.clear();
+        if let Some(parallel) = self.parallel.as_mut() {
+            parallel.reset();
+        }
+        let state = ZstdState {
+            buf: self.buf,
+            decoder: self.decoder,
+            parallel: self.parallel,
+        };
+        (self.source, Some(state))
+    }
 }
 
 impl<R: BufRead> FormatReader<R> for ZstdReader<'_, R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        &self.source
+    }
+
     fn get_mut(&mut self) -> &mut PeekReader<R> {
         &mut self.source
     }
@@ -71,6 +170,100 @@ impl<R: BufRead> FormatReader<R> for ZstdReader<'_, R> {
     fn into_inner(self) -> PeekReader<R> {
         self.source
     }
+
+    fn member(&self) -> u64 {
+        self.frames.saturating_sub(1)
+    }
+}
+
+impl<R: BufRead> ZstdReader<'_, R> {
+    /// Run the decoder once, writing to the output buffer.  Return false
+    /// if we've reached the end of the compressed data.
+    fn decompress<C: WriteBuf + ?Sized>(
+        &mut self,
+        output: &mut OutBuffer<'_, C>,
+    ) -> io::Result<bool> {
+        if self.start_of_frame {
+            if self.done || !has_magic(self.source.peek(4)?) {
+                // end of compressed data
+                return Ok(false);
+            }
+            self.frames += 1;
+            if let Some(handler) = &self.skippable {
+                if is_skippable(self.source.peek(4)?) {
+                    let (variant, payload) = read_skippable_frame(&mut self.source, true)?;
+                    handler.call(variant, &payload);
+                    self.done = self.single_frame;
+                    return Ok(true);
+                }
+            }
+            // skippable frames have no header
+            if let Some(header) = peek_frame_header(&mut self.source)? {
+                check_window(&header, self.window_log_max)?;
+                self.frame_header = Some(header);
+            }
+            self.start_of_frame = false;
+        }
+        let in_ = self.source.fill_buf()?;
+        if in_.is_empty() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "premature EOF reading zstd frame",
+            ));
+        }
+        let mut input = InBuffer::around(in_);
+        let remaining = self.decoder.run(&mut input, output)?;
+        let consumed = input.pos();
+        self.source.consume(consumed);
+        if remaining == 0 {
+            self.start_of_frame = true;
+            self.done = self.single_frame;
+        }
+        Ok(true)
+    }
+
+    /// Decompress until the buffer is non-empty or we reach the end of the
+    /// compressed data.  Return false in the latter case.
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.buf.is_empty() {
+            if let (Some(parallel), true) = (self.parallel.as_mut(), self.start_of_frame) {
+                match parallel.next_frame(&mut self.source)? {
+                    Frame::Data(buf, header) => {
+                        self.frames += 1;
+                        self.buf.put(buf);
+                        self.frame_header = Some(header);
+                        continue;
+                    }
+                    Frame::Skippable(variant, payload) => {
+                        self.frames += 1;
+                        if let Some(handler) = &self.skippable {
+                            handler.call(variant, &payload);
+                        }
+                        continue;
+                    }
+                    // decode this one ourselves
+                    Frame::Serial => (),
+                    Frame::End => return Ok(false),
+                }
+            }
+            let mut buf = self.buf.take(BUF_SIZE);
+            let ret = self.decompress(&mut OutBuffer::around(&mut buf));
+            self.buf.put(buf);
+            if !ret? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Return the next chunk of decompressed data without copying it, or
+    /// None at the end of the compressed data.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.split()))
+    }
 }
 
 impl<R: BufRead> Read for ZstdReader<'_, R> {
@@ -78,41 +271,214 @@ impl<R: BufRead> Read for ZstdReader<'_, R> {
         if out.is_empty() {
             return Ok(0);
         }
-        loop {
-            if !self.buf.is_empty() {
-                let count = self.buf.len().min(out.len());
-                self.buf.copy_to_slice(&mut out[..count]);
-                return Ok(count);
-            }
-            if self.start_of_frame {
-                if !has_magic(&mut self.source)? {
-                    // end of compressed data
+        if self.buf.is_empty() && out.len() >= BUF_SIZE && self.parallel.is_none() {
+            // nothing buffered and the caller's buffer is large; skip the
+            // copy
+            loop {
+                let mut output = OutBuffer::around(out);
+                if !self.decompress(&mut output)? {
                     return Ok(0);
                 }
-                self.start_of_frame = false;
+                if output.pos() > 0 {
+                    return Ok(output.pos());
+                }
             }
-            let in_ = self.source.fill_buf()?;
-            if in_.is_empty() {
-                return Err(Error::new(
-                    ErrorKind::UnexpectedEof,
-                    "premature EOF reading zstd frame",
-                ));
+        }
+        if !self.fill()? {
+            return Ok(0);
+        }
+        Ok(self.buf.read(out))
+    }
+}
+
+impl<R: BufRead> BufRead for ZstdReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill()?;
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+pub(crate) struct ZstdWriter<'a, W: Write> {
+    sink: W,
+    buf: Vec<u8>,
+    decoder: Decoder<'a>,
+    // at the start of a frame, the bytes we've seen of its magic number
+    magic: Option<Vec<u8>>,
+    skippable: Option<SkippableHandler>,
+    // within a skippable frame, its magic variant and the bytes after the
+    // magic number
+    skippable_frame: Option<(u8, Vec<u8>)>,
+    window_log_max: Option<u32>,
+    // the start of the current frame, to explain window size errors
+    header: Vec<u8>,
+}
+
+impl<'a, W: Write + fmt::Debug> fmt::Debug for ZstdWriter<'a, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdWriter")
+            .field("sink", &self.sink)
+            .field("magic", &self.magic)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> ZstdWriter<'_, W> {
+    pub(crate) fn new(sink: W, config: &DecompressBuilder) -> io::Result<Self> {
+        Ok(Self {
+            sink,
+            buf: vec![0; BUF_SIZE],
+            decoder: new_decoder(config)?,
+            magic: Some(Vec::with_capacity(4)),
+            skippable: config.zstd_skippable.clone(),
+            skippable_frame: None,
+            window_log_max: config.zstd_window_log_max,
+            header: Vec::with_capacity(MAX_FRAME_HEADER_LEN),
+        })
+    }
+
+    /// Buffer a skippable frame and pass it to the handler when complete.
+    fn write_skippable(&mut self, input: &[u8]) -> usize {
+        let (variant, frame) = self.skippable_frame.as_mut().unwrap();
+        // the frame size, then the payload
+        let mut count = input.len().min(4usize.saturating_sub(frame.len()));
+        frame.extend_from_slice(&input[..count]);
+        if frame.len() < 4 {
+            return count;
+        }
+        let len = 4 + u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+        let more = (input.len() - count).min(len - frame.len());
+        frame.extend_from_slice(&input[count..count + more]);
+        count += more;
+        if frame.len() == len {
+            if let Some(handler) = &self.skippable {
+                handler.call(*variant, &frame[4..]);
             }
-            // unfortunately we have to initialize to 0 for safety
-            // BUFFER_SIZE is very large; use a smaller buffer to avoid
-            // unneeded reinitialization
-            self.buf.resize(16384, 0);
-            let status = self.decoder.run_on_buffers(in_, &mut self.buf)?;
-            self.source.consume(status.bytes_read);
-            self.buf.truncate(status.bytes_written);
+            self.skippable_frame = None;
+            self.magic = Some(Vec::with_capacity(4));
+        }
+        count
+    }
+
+    /// If the decoder failed because the frame's window is too large,
+    /// return the typed error.  input is what the decoder was given.
+    fn window_error(&self, input: &[u8]) -> Option<Error> {
+        let mut header = self.header.clone();
+        let count = input.len().min(MAX_FRAME_HEADER_LEN - header.len());
+        header.extend_from_slice(&input[..count]);
+        let (header, _) = ZstdFrameHeader::parse(&header)?;
+        check_window(&header, self.window_log_max).err()
+    }
+
+    fn decompress(&mut self, input: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        loop {
+            let status = match self
+                .decoder
+                .run_on_buffers(&input[consumed..], &mut self.buf)
+            {
+                Ok(status) => status,
+                Err(e) => return Err(self.window_error(&input[consumed..]).unwrap_or(e)),
+            };
+            let count = status
+                .bytes_read
+                .min(MAX_FRAME_HEADER_LEN - self.header.len());
+            self.header
+                .extend_from_slice(&input[consumed..consumed + count]);
+            consumed += status.bytes_read;
+            self.sink.write_all(&self.buf[..status.bytes_written])?;
             if status.remaining == 0 {
-                self.start_of_frame = true;
+                self.magic = Some(Vec::with_capacity(4));
+                return Ok(consumed);
+            }
+            if consumed == input.len() && status.bytes_written < self.buf.len() {
+                return Ok(consumed);
             }
         }
     }
 }
 
-fn has_magic<R: BufRead>(source: &mut PeekReader<R>) -> io::Result<bool> {
-    let val = u32::from_le_bytes(source.peek(4)?.try_into().unwrap_or_default());
-    Ok(val == MAGICNUMBER || val & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START)
+impl<W: Write> FormatWriter<W> for ZstdWriter<'_, W> {
+    fn write_compressed(&mut self, input: &[u8]) -> io::Result<usize> {
+        if self.skippable_frame.is_some() {
+            return Ok(self.write_skippable(input));
+        }
+        if self.magic.is_none() {
+            let count = self.decompress(input)?;
+            if count > 0 || self.magic.is_none() {
+                return Ok(count);
+            }
+        }
+        // We're at the start of a frame.  Check whether it really is one.
+        let magic = self.magic.as_mut().unwrap();
+        let count = input.len().min(4 - magic.len());
+        magic.extend(&input[..count]);
+        if magic.len() < 4 {
+            return Ok(count);
+        }
+        if !has_magic(magic) {
+            // end of compressed data
+            return Ok(0);
+        }
+        if self.skippable.is_some() && is_skippable(magic) {
+            self.skippable_frame = Some((magic[0] & 0x0f, Vec::new()));
+            self.magic = None;
+            return Ok(count);
+        }
+        let magic = self.magic.take().unwrap();
+        self.header.clear();
+        if self.decompress(&magic)? != magic.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "couldn't decompress zstd frame header",
+            ));
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    fn finish(self) -> io::Result<(W, bool)> {
+        match self.magic {
+            Some(magic) => Ok((self.sink, !magic.is_empty())),
+            None => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "premature EOF reading zstd frame",
+            )),
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        &self.sink
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+}
+
+fn new_decoder<'a>(config: &DecompressBuilder) -> io::Result<Decoder<'a>> {
+    let mut decoder = Decoder::new()?;
+    if let Some(max) = config.zstd_window_log_max {
+        decoder.set_parameter(DParameter::WindowLogMax(max))?;
+    }
+    Ok(decoder)
+}
+
+/// Sum the content sizes recorded in the headers of the zstd frames.
+pub(super) fn size_hint(mut input: &[u8]) -> Option<u64> {
+    let mut total = 0u64;
+    while input.len() >= 4 && has_magic(&input[..4]) {
+        match get_frame_content_size(input) {
+            CONTENTSIZE_ERROR | CONTENTSIZE_UNKNOWN => return None,
+            size => total = total.checked_add(size)?,
+        }
+        input = input.get(find_frame_compressed_size(input).ok()?..)?;
+    }
+    Some(total)
 }
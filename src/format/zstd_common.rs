@@ -0,0 +1,254 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Parts of the zstd frame format shared by the libzstd and pure-Rust
+// backends.
+
+use alloc::sync::Arc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::io::{self, BufRead, Error, ErrorKind};
+use crate::{DecompressError, PeekReader};
+
+pub(crate) const MAGICNUMBER: u32 = 0xfd2f_b528;
+pub(crate) const MAGIC_SKIPPABLE_START: u32 = 0x184d_2a50;
+pub(crate) const MAGIC_SKIPPABLE_MASK: u32 = 0xffff_fff0;
+
+type SkippableCallback = dyn Fn(u8, &[u8]) + Send + Sync;
+
+/// Callback that receives the magic variant and payload of each skippable
+/// frame.
+#[derive(Clone)]
+pub(crate) struct SkippableHandler(Arc<SkippableCallback>);
+
+impl SkippableHandler {
+    pub(crate) fn new<F: Fn(u8, &[u8]) + Send + Sync + 'static>(callback: F) -> Self {
+        Self(Arc::new(callback))
+    }
+
+    pub(crate) fn call(&self, variant: u8, payload: &[u8]) {
+        (self.0)(variant, payload)
+    }
+}
+
+impl fmt::Debug for SkippableHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkippableHandler").finish_non_exhaustive()
+    }
+}
+
+/// Header of a zstd frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ZstdFrameHeader {
+    /// Decompressed size of the frame, if recorded.
+    pub content_size: Option<u64>,
+    /// Size of the history buffer the decoder needs, which dominates its
+    /// memory usage.
+    pub window_size: u64,
+    pub dictionary_id: Option<u32>,
+    /// Whether the frame ends with a checksum of the decompressed data.
+    pub checksum: bool,
+    /// Whether the frame is decoded as a single segment, in which case the
+    /// window size is the content size.
+    pub single_segment: bool,
+}
+
+impl ZstdFrameHeader {
+    /// Parse the frame header at the start of the input, and return it
+    /// with its length.  Return None if the input doesn't start with a
+    /// complete, valid frame header.
+    pub(crate) fn parse(input: &[u8]) -> Option<(Self, usize)> {
+        if u32::from_le_bytes(input.get(..4)?.try_into().unwrap()) != MAGICNUMBER {
+            return None;
+        }
+        let descriptor = *input.get(4)?;
+        if descriptor & 0x08 != 0 {
+            // reserved bit
+            return None;
+        }
+        let single_segment = descriptor & 0x20 != 0;
+        let mut pos = 5;
+        let window_descriptor = if single_segment {
+            None
+        } else {
+            pos += 1;
+            Some(*input.get(5)?)
+        };
+        let dictionary_id_len = [0, 1, 2, 4][usize::from(descriptor & 3)];
+        let mut dictionary_id = [0; 4];
+        dictionary_id[..dictionary_id_len]
+            .copy_from_slice(input.get(pos..pos + dictionary_id_len)?);
+        pos += dictionary_id_len;
+        let fcs_len = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => 0,
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+        let mut fcs = [0; 8];
+        fcs[..fcs_len].copy_from_slice(input.get(pos..pos + fcs_len)?);
+        pos += fcs_len;
+        let content_size = match fcs_len {
+            0 => None,
+            2 => Some(u64::from_le_bytes(fcs) + 256),
+            _ => Some(u64::from_le_bytes(fcs)),
+        };
+        let window_size = match window_descriptor {
+            Some(descriptor) => {
+                let base = 1u64 << (10 + (descriptor >> 3));
+                base + base / 8 * u64::from(descriptor & 7)
+            }
+            // single-segment frames always record the content size
+            None => content_size?,
+        };
+        let header = Self {
+            content_size,
+            window_size,
+            dictionary_id: Some(u32::from_le_bytes(dictionary_id)).filter(|id| *id != 0),
+            checksum: descriptor & 0x04 != 0,
+            single_segment,
+        };
+        Some((header, pos))
+    }
+}
+
+/// Parse the header of the zstd frame at the start of the source without
+/// consuming it.  Return None if there's no complete, valid frame header.
+pub(crate) fn peek_frame_header<R: BufRead>(
+    source: &mut PeekReader<R>,
+) -> io::Result<Option<ZstdFrameHeader>> {
+    let descriptor = match source.peek(5)?.get(4) {
+        Some(descriptor) => *descriptor,
+        None => return Ok(None),
+    };
+    let len = frame_header_len(descriptor);
+    Ok(ZstdFrameHeader::parse(source.peek(len)?).map(|(header, _)| header))
+}
+
+/// Length of a frame header, including the magic number, from its Frame
+/// Header Descriptor.
+pub(crate) fn frame_header_len(descriptor: u8) -> usize {
+    let fcs_len = match descriptor >> 6 {
+        0 => usize::from(descriptor & 0x20 != 0),
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    5 + usize::from(descriptor & 0x20 == 0) + [0, 1, 2, 4][usize::from(descriptor & 3)] + fcs_len
+}
+
+/// Find the end of a frame by walking its block headers, starting at the
+/// first block, and return the offset past the blocks and checksum.  Block
+/// headers are read with read_header, given their offset.  Return None
+/// if the end would be past limit.
+#[cfg(feature = "std")]
+pub(crate) fn find_frame_end<F>(
+    start: u64,
+    checksum: bool,
+    limit: u64,
+    mut read_header: F,
+) -> crate::Result<Option<u64>>
+where
+    F: FnMut(u64) -> crate::Result<[u8; 3]>,
+{
+    let mut pos = start;
+    loop {
+        let header = read_header(pos)?;
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        let size = match (header >> 1) & 3 {
+            // RLE
+            1 => 1,
+            3 => return Err(Error::new(ErrorKind::InvalidData, "reserved zstd block type").into()),
+            // raw or compressed
+            _ => u64::from(header >> 3),
+        };
+        pos = pos.saturating_add(3 + size);
+        if pos > limit {
+            return Ok(None);
+        }
+        if header & 1 != 0 {
+            // last block
+            break;
+        }
+    }
+    if checksum {
+        pos = pos.saturating_add(4);
+    }
+    Ok(Some(pos).filter(|pos| *pos <= limit))
+}
+
+/// Fail if the frame's window is larger than 2^window_log_max bytes.
+pub(crate) fn check_window(
+    header: &ZstdFrameHeader,
+    window_log_max: Option<u32>,
+) -> io::Result<()> {
+    match window_log_max {
+        Some(max) if header.window_size > 1u64.checked_shl(max).unwrap_or(u64::MAX) => {
+            Err(DecompressError::ZstdWindowTooLarge {
+                window_size: header.window_size,
+                window_log_max: max,
+            }
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn has_magic(peek: &[u8]) -> bool {
+    let val = u32::from_le_bytes(peek.try_into().unwrap_or_default());
+    val == MAGICNUMBER || val & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START
+}
+
+pub(crate) fn is_skippable(peek: &[u8]) -> bool {
+    let val = u32::from_le_bytes(peek.try_into().unwrap_or_default());
+    val & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START
+}
+
+/// Consume the skippable frame at the start of the source and return its
+/// magic variant, plus its payload if requested.
+pub(crate) fn read_skippable_frame<R: BufRead>(
+    source: &mut PeekReader<R>,
+    keep_payload: bool,
+) -> io::Result<(u8, Vec<u8>)> {
+    let header = source.peek(8)?;
+    if header.len() < 8 {
+        return Err(premature_eof());
+    }
+    let variant = header[0] & 0x0f;
+    let mut remaining = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    source.consume(8);
+    // the size is untrusted; don't preallocate it
+    let mut payload = Vec::new();
+    while remaining > 0 {
+        let buf = source.fill_buf()?;
+        let count = buf.len().min(remaining);
+        if count == 0 {
+            return Err(premature_eof());
+        }
+        if keep_payload {
+            payload.extend_from_slice(&buf[..count]);
+        }
+        source.consume(count);
+        remaining -= count;
+    }
+    Ok((variant, payload))
+}
+
+pub(crate) fn premature_eof() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "premature EOF reading zstd frame")
+}
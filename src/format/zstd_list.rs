@@ -0,0 +1,121 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Listing the frames of a zstd file without decompressing them.  Frames
+// don't record their compressed size, so we find the end of each frame by
+// walking its block headers, seeking past the block contents.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::format::zstd_common::{
+    find_frame_end, frame_header_len, has_magic, premature_eof, ZstdFrameHeader, MAGICNUMBER,
+    MAGIC_SKIPPABLE_MASK, MAGIC_SKIPPABLE_START,
+};
+use crate::{DecompressError, Result};
+
+/// Maximum length of a frame header.
+const MAX_HEADER_SIZE: u64 = 18;
+
+/// A zstd frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ZstdFrame {
+    /// Offset of the frame from the start of the file.
+    pub offset: u64,
+    pub compressed_size: u64,
+    pub header: ZstdFrameHeader,
+}
+
+/// List the frames of the zstd file between the source's current position
+/// and its end, without decompressing them.  Skippable frames are omitted,
+/// and listing stops at any trailing data.  Offsets are relative to the
+/// starting position, which is restored afterward.  Fails with
+/// DecompressError::UnrecognizedFormat if the source isn't zstd.
+pub fn zstd_list<R: Read + Seek>(source: &mut R) -> Result<Vec<ZstdFrame>> {
+    let start = source.stream_position()?;
+    let ret = Lister { source, start }.list();
+    source.seek(SeekFrom::Start(start))?;
+    ret
+}
+
+struct Lister<'a, R: Read + Seek> {
+    source: &'a mut R,
+    start: u64,
+}
+
+impl<R: Read + Seek> Lister<'_, R> {
+    fn list(&mut self) -> Result<Vec<ZstdFrame>> {
+        if !has_magic(&self.read_at(0, 4)?) {
+            return Err(DecompressError::UnrecognizedFormat);
+        }
+        let end = self.source.seek(SeekFrom::End(0))? - self.start;
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        loop {
+            let peek = self.read_at(offset, MAX_HEADER_SIZE)?;
+            let magic = match peek.get(..4) {
+                Some(magic) => u32::from_le_bytes(magic.try_into().unwrap()),
+                // end of compressed data
+                None => break,
+            };
+            let next = if magic & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START {
+                let size = peek.get(4..8).ok_or_else(premature_eof)?;
+                offset + 8 + u64::from(u32::from_le_bytes(size.try_into().unwrap()))
+            } else if magic == MAGICNUMBER {
+                let descriptor = *peek.get(4).ok_or_else(premature_eof)?;
+                if peek.len() < frame_header_len(descriptor) {
+                    return Err(premature_eof().into());
+                }
+                let (header, len) = ZstdFrameHeader::parse(&peek).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "invalid zstd frame header")
+                })?;
+                let size = self.frame_size(offset + len as u64, header.checksum, end)? + len as u64;
+                frames.push(ZstdFrame {
+                    offset,
+                    compressed_size: size,
+                    header,
+                });
+                offset + size
+            } else {
+                // trailing data
+                break;
+            };
+            if next > end {
+                return Err(premature_eof().into());
+            }
+            offset = next;
+        }
+        Ok(frames)
+    }
+
+    /// Return the size of the blocks and checksum starting at the
+    /// specified offset, which must end by the end of the input.
+    fn frame_size(&mut self, start: u64, checksum: bool, end: u64) -> Result<u64> {
+        find_frame_end(start, checksum, end, |pos| {
+            self.read_at(pos, 3)?
+                .try_into()
+                .map_err(|_| premature_eof().into())
+        })?
+        .map(|frame_end| frame_end - start)
+        .ok_or_else(|| premature_eof().into())
+    }
+
+    /// Read up to len bytes, stopping early at EOF.
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        self.source.seek(SeekFrom::Start(self.start + offset))?;
+        let mut buf = Vec::new();
+        (&mut *self.source).take(len).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Parallel decoding of independent zstd frames, such as those written by
+// pzstd.  zstd frames don't record their compressed size, so we find the
+// end of each frame by walking its block headers, peeking ahead in the
+// source without consuming anything.  Complete frames are decoded on a
+// worker pool and returned in order.  We only do this for frames whose
+// header records a modest decompressed size; other frames are left in the
+// source for ZstdReader to decode in streaming mode.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Error, ErrorKind};
+
+use crate::format::pool::{JobResult, WorkerPool};
+use crate::format::zstd_common::{
+    find_frame_end, frame_header_len, premature_eof, read_skippable_frame, ZstdFrameHeader,
+    MAGICNUMBER, MAGIC_SKIPPABLE_MASK, MAGIC_SKIPPABLE_START,
+};
+use crate::PeekReader;
+
+/// Largest frame, compressed or decompressed, that we decode in parallel.
+const MAX_FRAME_SIZE: usize = 16 << 20;
+
+pub(crate) enum Frame {
+    /// A decoded frame and its header.
+    Data(Vec<u8>, ZstdFrameHeader),
+    /// The magic variant and payload of a skippable frame.
+    Skippable(u8, Vec<u8>),
+    /// The source is at the start of a frame that the caller should
+    /// decode itself.
+    Serial,
+    /// End of the compressed data.
+    End,
+}
+
+enum Pending {
+    Frame(JobResult<Vec<u8>>, ZstdFrameHeader),
+    Skippable(u8, Vec<u8>),
+    Serial,
+    Error(Error),
+}
+
+pub(crate) struct ParallelDecoder {
+    pool: WorkerPool<(Vec<u8>, usize), Vec<u8>>,
+    max_pending: usize,
+    pending: VecDeque<Pending>,
+    // no more input to scan
+    done: bool,
+    // return skippable frames to the caller
+    keep_skippable: bool,
+    // leave frames with larger windows to the caller, which rejects them
+    max_window: Option<u64>,
+}
+
+impl ParallelDecoder {
+    pub(crate) fn new(
+        threads: u32,
+        keep_skippable: bool,
+        window_log_max: Option<u32>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            pool: WorkerPool::new(
+                "zstd decoder",
+                threads,
+                |(frame, size): (Vec<u8>, usize)| zstd::bulk::decompress(&frame, size),
+            )?,
+            // keep the workers busy while we wait for the oldest frame
+            max_pending: 2 * threads as usize,
+            pending: VecDeque::new(),
+            done: false,
+            keep_skippable,
+            max_window: window_log_max.map(|max| 1u64.checked_shl(max).unwrap_or(u64::MAX)),
+        })
+    }
+
+    /// Discard all state so the decoder can be used with a new source.
+    /// Frames already queued are decoded, but the results are dropped.
+    pub(crate) fn reset(&mut self) {
+        self.pending.clear();
+        self.done = false;
+    }
+
+    /// Return the next frame.  The source must be at a frame boundary.
+    pub(crate) fn next_frame<R: BufRead>(
+        &mut self,
+        source: &mut PeekReader<R>,
+    ) -> io::Result<Frame> {
+        // stop scanning at a frame the caller needs to decode
+        while self.pending.len() < self.max_pending
+            && !self.done
+            && !matches!(self.pending.back(), Some(Pending::Serial))
+        {
+            if let Err(e) = self.scan(source) {
+                self.pending.push_back(Pending::Error(e));
+                self.done = true;
+            }
+        }
+        match self.pending.pop_front() {
+            Some(Pending::Frame(result, header)) => Ok(Frame::Data(result.wait()?, header)),
+            Some(Pending::Skippable(variant, payload)) => Ok(Frame::Skippable(variant, payload)),
+            Some(Pending::Serial) => Ok(Frame::Serial),
+            Some(Pending::Error(e)) => Err(e),
+            None => Ok(Frame::End),
+        }
+    }
+
+    /// Queue the next frame.
+    fn scan<R: BufRead>(&mut self, source: &mut PeekReader<R>) -> io::Result<()> {
+        let magic = match source.peek(4)?.try_into() {
+            Ok(magic) => u32::from_le_bytes(magic),
+            Err(_) => {
+                // end of compressed data
+                self.done = true;
+                return Ok(());
+            }
+        };
+        if magic & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START {
+            let (variant, payload) = read_skippable_frame(source, self.keep_skippable)?;
+            if self.keep_skippable {
+                self.pending.push_back(Pending::Skippable(variant, payload));
+            }
+            return Ok(());
+        }
+        if magic != MAGICNUMBER {
+            // end of compressed data
+            self.done = true;
+            return Ok(());
+        }
+        let header = frame_size(source)?
+            .filter(|(header, _, _)| header.window_size <= self.max_window.unwrap_or(u64::MAX));
+        match header {
+            Some((header, compressed, decompressed)) => {
+                let frame = source.peek(compressed)?.to_vec();
+                source.consume(compressed);
+                let result = self.pool.submit((frame, decompressed))?;
+                self.pending.push_back(Pending::Frame(result, header));
+            }
+            None => self.pending.push_back(Pending::Serial),
+        }
+        Ok(())
+    }
+}
+
+/// Parse the header of the frame at the start of the source and find its
+/// compressed and decompressed sizes.  Return None if the frame is too
+/// large to decode in parallel or doesn't record its decompressed size.
+fn frame_size<R: BufRead>(
+    source: &mut PeekReader<R>,
+) -> io::Result<Option<(ZstdFrameHeader, usize, usize)>> {
+    let descriptor = peek_exact(source, 5)?[4];
+    let peek = peek_exact(source, frame_header_len(descriptor))?;
+    let (header, len) = ZstdFrameHeader::parse(peek)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid zstd frame header"))?;
+    let decompressed = match header.content_size {
+        Some(size) if size <= MAX_FRAME_SIZE as u64 => size as usize,
+        _ => return Ok(None),
+    };
+    let end = find_frame_end(len as u64, header.checksum, MAX_FRAME_SIZE as u64, |pos| {
+        let pos = pos as usize;
+        Ok(peek_exact(source, pos + 3)?[pos..].try_into().unwrap())
+    })?;
+    match end {
+        Some(end) => {
+            peek_exact(source, end as usize)?;
+            Ok(Some((header, end as usize, decompressed)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn peek_exact<R: BufRead>(source: &mut PeekReader<R>, amt: usize) -> io::Result<&[u8]> {
+    let buf = source.peek(amt)?;
+    if buf.len() < amt {
+        return Err(premature_eof());
+    }
+    Ok(buf)
+}
@@ -0,0 +1,400 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Pure-Rust zstd backend using ruzstd.  ruzstd decodes one frame at a time
+// and reads exactly the bytes of the frame, so we walk the frames
+// ourselves, skipping skippable frames and stopping at anything that
+// isn't a frame, as with libzstd.  ruzstd doesn't verify frame checksums,
+// so we do that too.  It can only pull from a Read, so ZstdWriter runs
+// the reader on a thread.  Without std, ruzstd has its own Read trait and
+// error type.
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, string::ToString};
+use bytes::Bytes;
+use core::fmt;
+use core::marker::PhantomData;
+#[cfg(not(feature = "std"))]
+use ruzstd::io::Read as _;
+use ruzstd::{BlockDecodingStrategy, FrameDecoder};
+
+#[cfg(feature = "std")]
+use crate::format::pull_writer::{Input, PullFormat, PullWriter};
+use crate::format::zstd_common::{
+    check_window, has_magic, peek_frame_header, read_skippable_frame, SkippableHandler,
+    ZstdFrameHeader, MAGICNUMBER, MAGIC_SKIPPABLE_MASK, MAGIC_SKIPPABLE_START,
+};
+use crate::format::{read_into, OutputBuffer};
+use crate::io::{self, BufRead, Error, ErrorKind, Read};
+use crate::{DecompressBuilder, FormatReader, PeekReader, Result};
+
+/// Size of the internal output buffer, and the amount of output to decode
+/// at a time.
+const BUF_SIZE: usize = 16384;
+
+#[cfg(feature = "std")]
+pub(crate) type ZstdWriter<'a, W> = PullWriter<W, ZstdReader<'static, Input>>;
+
+pub(crate) struct ZstdReader<'a, R: BufRead> {
+    source: PeekReader<R>,
+    // only used for BufRead
+    buf: OutputBuffer,
+    decoder: FrameDecoder,
+    start_of_frame: bool,
+    frame_header: Option<ZstdFrameHeader>,
+    // stop at the end of the first frame
+    single_frame: bool,
+    // no more frames to decode
+    done: bool,
+    // frames started, including skippable frames
+    frames: u64,
+    skippable: Option<SkippableHandler>,
+    window_log_max: Option<u32>,
+    // for compatibility with the libzstd backend
+    phantom: PhantomData<&'a ()>,
+}
+
+/// Idle decoder state, kept for reuse with another source.
+pub(crate) struct ZstdState<'a> {
+    buf: OutputBuffer,
+    decoder: FrameDecoder,
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, R: BufRead + fmt::Debug> fmt::Debug for ZstdReader<'a, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdReader")
+            .field("source", &self.source)
+            .field("buf", &self.buf)
+            .field("start_of_frame", &self.start_of_frame)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, R: BufRead> ZstdReader<'a, R> {
+    pub(crate) fn detect(source: &mut PeekReader<R>) -> Result<bool> {
+        Ok(has_magic(source.peek(4)?))
+    }
+
+    // ruzstd always decodes serially
+    pub(crate) fn with_state(
+        source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<ZstdState<'a>>,
+    ) -> Result<Self> {
+        let mut reader = Self::new(source, config, state);
+        reader.single_frame = config.single_member;
+        reader.frame_header = peek_frame_header(&mut reader.source)?;
+        Ok(reader)
+    }
+
+    fn new(
+        source: PeekReader<R>,
+        config: &DecompressBuilder,
+        state: Option<ZstdState<'a>>,
+    ) -> Self {
+        let (buf, decoder) = match state {
+            Some(state) => (state.buf, state.decoder),
+            None => (OutputBuffer::default(), FrameDecoder::new()),
+        };
+        Self {
+            source,
+            buf,
+            decoder,
+            start_of_frame: true,
+            frame_header: None,
+            single_frame: false,
+            done: false,
+            frames: 0,
+            skippable: config.zstd_skippable.clone(),
+            window_log_max: config.zstd_window_log_max,
+            phantom: PhantomData,
+        }
+    }
+
+    pub(crate) fn frame_header(&self) -> Option<&ZstdFrameHeader> {
+        self.frame_header.as_ref()
+    }
+
+    /// Return the source, plus the decoder state for reuse.  The decoder
+    /// is reset when it starts the next frame.
+    pub(crate) fn into_state(mut self) -> (PeekReader<R>, Option<ZstdState<'a>>) {
+        self.buf.clear();
+        let state = ZstdState {
+            buf: self.buf,
+            decoder: self.decoder,
+            phantom: PhantomData,
+        };
+        (self.source, Some(state))
+    }
+}
+
+impl<R: BufRead> FormatReader<R> for ZstdReader<'_, R> {
+    fn get_ref(&self) -> &PeekReader<R> {
+        &self.source
+    }
+
+    fn get_mut(&mut self) -> &mut PeekReader<R> {
+        &mut self.source
+    }
+
+    fn into_inner(self) -> PeekReader<R> {
+        self.source
+    }
+
+    fn member(&self) -> u64 {
+        self.frames.saturating_sub(1)
+    }
+}
+
+impl<R: BufRead> ZstdReader<'_, R> {
+    /// Skip any skippable frames and start decoding the next frame.
+    /// Return false if we've reached the end of the compressed data.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        loop {
+            let magic = match self.source.peek(4)?.try_into() {
+                Ok(magic) => u32::from_le_bytes(magic),
+                // end of compressed data
+                Err(_) => return Ok(false),
+            };
+            if magic & MAGIC_SKIPPABLE_MASK == MAGIC_SKIPPABLE_START {
+                self.frames += 1;
+                let (variant, payload) =
+                    read_skippable_frame(&mut self.source, self.skippable.is_some())?;
+                if let Some(handler) = &self.skippable {
+                    handler.call(variant, &payload);
+                }
+                if self.single_frame {
+                    self.done = true;
+                    return Ok(false);
+                }
+            } else if magic == MAGICNUMBER {
+                self.frames += 1;
+                self.frame_header = peek_frame_header(&mut self.source)?;
+                if let Some(header) = &self.frame_header {
+                    check_window(header, self.window_log_max)?;
+                }
+                self.decoder
+                    .reset(decoder_source(&mut self.source))
+                    .map_err(invalid_data)?;
+                self.start_of_frame = false;
+                return Ok(true);
+            } else {
+                // end of compressed data
+                return Ok(false);
+            }
+        }
+    }
+
+    fn decompress(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.start_of_frame && !self.next_frame()? {
+                return Ok(0);
+            }
+            let ret = self.decoder.read(out);
+            #[cfg(not(feature = "std"))]
+            let ret = ret.map_err(from_decoder_error);
+            let count = ret?;
+            if count > 0 {
+                return Ok(count);
+            }
+            if self.decoder.is_finished() {
+                // all output returned; the checksum covers all of it
+                if let Some(checksum) = self.decoder.get_checksum_from_data() {
+                    if self.decoder.get_calculated_checksum() != Some(checksum) {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "zstd frame checksum mismatch",
+                        ));
+                    }
+                }
+                self.start_of_frame = true;
+                self.done = self.single_frame;
+            } else {
+                self.decoder
+                    .decode_blocks(
+                        decoder_source(&mut self.source),
+                        BlockDecodingStrategy::UptoBytes(BUF_SIZE),
+                    )
+                    .map_err(invalid_data)?;
+            }
+        }
+    }
+
+    /// Decompress into the buffer if it's empty.  Return false if we've
+    /// reached the end of the compressed data.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.buf.is_empty() {
+            let mut buf = self.buf.take(BUF_SIZE);
+            let ret = read_into(&mut buf, |out| self.decompress(out));
+            self.buf.put(buf);
+            return Ok(ret? > 0);
+        }
+        Ok(true)
+    }
+
+    /// Return the next chunk of decompressed data without copying it, or
+    /// None at the end of the compressed data.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if !self.fill()? {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.split()))
+    }
+}
+
+impl<R: BufRead> Read for ZstdReader<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.buf.is_empty() {
+            return Ok(self.buf.read(out));
+        }
+        self.decompress(out)
+    }
+}
+
+impl<R: BufRead> BufRead for ZstdReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.fill()?;
+        Ok(self.buf.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.buf.consume(amt)
+    }
+}
+
+#[cfg(feature = "std")]
+impl PullFormat for ZstdReader<'static, Input> {
+    fn start(source: PeekReader<Input>, config: &DecompressBuilder) -> Result<Self> {
+        // there's no input yet, so don't wait for the frame header
+        Ok(Self::new(source, config, None))
+    }
+}
+
+fn invalid_data(err: ruzstd::frame_decoder::FrameDecoderError) -> Error {
+    #[cfg(not(feature = "std"))]
+    let err = err.to_string();
+    Error::new(ErrorKind::InvalidData, err)
+}
+
+/// ruzstd's view of the source.
+#[cfg(feature = "std")]
+fn decoder_source<R: BufRead>(source: &mut PeekReader<R>) -> &mut PeekReader<R> {
+    source
+}
+
+#[cfg(not(feature = "std"))]
+fn decoder_source<R: BufRead>(source: &mut PeekReader<R>) -> Source<'_, R> {
+    Source(source)
+}
+
+/// Adapter from our Read to ruzstd's, for builds without std.
+#[cfg(not(feature = "std"))]
+struct Source<'s, R: BufRead>(&'s mut PeekReader<R>);
+
+#[cfg(not(feature = "std"))]
+impl<R: BufRead> ruzstd::io::Read for Source<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> core::result::Result<usize, ruzstd::io::Error> {
+        use ruzstd::io::ErrorKind as DecoderKind;
+        self.0.read(out).map_err(|err| {
+            let kind = match err.kind() {
+                ErrorKind::Interrupted => DecoderKind::Interrupted,
+                ErrorKind::UnexpectedEof => DecoderKind::UnexpectedEof,
+                _ => DecoderKind::Other,
+            };
+            ruzstd::io::Error::new(kind, Box::new(err))
+        })
+    }
+}
+
+#[cfg(not(feature = "std"))]
+fn from_decoder_error(err: ruzstd::io::Error) -> Error {
+    use ruzstd::io::ErrorKind as DecoderKind;
+    let kind = match err.kind() {
+        DecoderKind::Interrupted => ErrorKind::Interrupted,
+        DecoderKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+        _ => ErrorKind::Other,
+    };
+    Error::new(kind, err.to_string())
+}
+
+/// Sum the content sizes recorded in the headers of the zstd frames.
+pub(super) fn size_hint(mut input: &[u8]) -> Option<u64> {
+    let mut total = 0u64;
+    while input.len() >= 4 && has_magic(&input[..4]) {
+        let magic = u32::from_le_bytes(input[..4].try_into().unwrap());
+        let len = if magic == MAGICNUMBER {
+            let (size, len) = frame_size(input)?;
+            total = total.checked_add(size)?;
+            len
+        } else {
+            let size = u32::from_le_bytes(input.get(4..8)?.try_into().unwrap());
+            8 + size as usize
+        };
+        input = input.get(len..)?;
+    }
+    Some(total)
+}
+
+/// Return the content size and compressed length of the frame at the
+/// start of the input, or None if the frame doesn't record its content
+/// size.
+fn frame_size(input: &[u8]) -> Option<(u64, usize)> {
+    let descriptor = *input.get(4)?;
+    let fcs_flag = descriptor >> 6;
+    let single_segment = descriptor & 0x20 != 0;
+    let fcs_len = match fcs_flag {
+        0 if single_segment => 1,
+        0 => return None,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let mut pos = 5 + usize::from(!single_segment) + [0, 1, 2, 4][usize::from(descriptor & 3)];
+    let mut fcs = [0; 8];
+    fcs[..fcs_len].copy_from_slice(input.get(pos..pos + fcs_len)?);
+    let mut size = u64::from_le_bytes(fcs);
+    if fcs_flag == 1 {
+        size += 256;
+    }
+    pos += fcs_len;
+
+    loop {
+        let header = input.get(pos..pos + 3)?;
+        let header = u32::from_le_bytes([header[0], header[1], header[2], 0]);
+        pos += 3 + match (header >> 1) & 3 {
+            // RLE
+            1 => 1,
+            // reserved
+            3 => return None,
+            // raw or compressed
+            _ => (header >> 3) as usize,
+        };
+        if header & 1 != 0 {
+            // last block
+            break;
+        }
+    }
+    if descriptor & 0x04 != 0 {
+        // checksum
+        pos += 4;
+    }
+    Some((size, pos))
+}
@@ -0,0 +1,330 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! I/O traits used by the readers.  With the `std` feature these are the
+//! `std::io` items.  Without it, they're minimal equivalents for core +
+//! alloc, which sources implement in place of `std::io::BufRead`.
+
+#[cfg(feature = "std")]
+pub use std::io::{BufRead, Error, ErrorKind, Read, Result};
+
+#[cfg(not(feature = "std"))]
+pub use self::no_std::*;
+
+#[cfg(not(feature = "std"))]
+mod no_std {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::error::Error as StdError;
+    use core::fmt;
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// The subset of `std::io::ErrorKind` that decoders report.
+    #[non_exhaustive]
+    #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+    pub enum ErrorKind {
+        InvalidData,
+        InvalidInput,
+        UnexpectedEof,
+        Interrupted,
+        OutOfMemory,
+        Unsupported,
+        Other,
+    }
+
+    impl ErrorKind {
+        fn as_str(&self) -> &'static str {
+            use ErrorKind::*;
+            match self {
+                InvalidData => "invalid data",
+                InvalidInput => "invalid input parameter",
+                UnexpectedEof => "unexpected end of file",
+                Interrupted => "operation interrupted",
+                OutOfMemory => "out of memory",
+                Unsupported => "unsupported",
+                Other => "other error",
+            }
+        }
+    }
+
+    impl fmt::Display for ErrorKind {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.as_str())
+        }
+    }
+
+    /// An I/O error, with the same API as the corresponding parts of
+    /// `std::io::Error`.
+    pub struct Error {
+        kind: ErrorKind,
+        error: Option<Box<dyn StdError + Send + Sync>>,
+    }
+
+    impl Error {
+        pub fn new<E: Into<Box<dyn StdError + Send + Sync>>>(kind: ErrorKind, error: E) -> Self {
+            Self {
+                kind,
+                error: Some(error.into()),
+            }
+        }
+
+        pub fn kind(&self) -> ErrorKind {
+            self.kind
+        }
+
+        pub fn get_ref(&self) -> Option<&(dyn StdError + Send + Sync + 'static)> {
+            self.error.as_deref()
+        }
+
+        pub fn get_mut(&mut self) -> Option<&mut (dyn StdError + Send + Sync + 'static)> {
+            self.error.as_deref_mut()
+        }
+
+        pub fn into_inner(self) -> Option<Box<dyn StdError + Send + Sync>> {
+            self.error
+        }
+    }
+
+    impl From<ErrorKind> for Error {
+        fn from(kind: ErrorKind) -> Self {
+            Self { kind, error: None }
+        }
+    }
+
+    impl fmt::Debug for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Error")
+                .field("kind", &self.kind)
+                .field("error", &self.error)
+                .finish()
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.error {
+                Some(error) => error.fmt(f),
+                None => self.kind.fmt(f),
+            }
+        }
+    }
+
+    impl StdError for Error {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            self.error.as_ref()?.source()
+        }
+    }
+
+    pub trait Read {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+        fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.read(buf) {
+                    Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Ok(count) => buf = &mut buf[count..],
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+
+        fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+            let start = buf.len();
+            loop {
+                let len = buf.len();
+                buf.reserve(32);
+                buf.resize(buf.capacity(), 0);
+                let ret = self.read(&mut buf[len..]);
+                buf.truncate(len + *ret.as_ref().unwrap_or(&0));
+                match ret {
+                    Ok(0) => return Ok(len - start),
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    pub trait BufRead: Read {
+        fn fill_buf(&mut self) -> Result<&[u8]>;
+        fn consume(&mut self, amt: usize);
+    }
+
+    impl Read for &[u8] {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let count = self.len().min(buf.len());
+            buf[..count].copy_from_slice(&self[..count]);
+            *self = &self[count..];
+            Ok(count)
+        }
+    }
+
+    impl BufRead for &[u8] {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            Ok(self)
+        }
+
+        fn consume(&mut self, amt: usize) {
+            *self = &self[amt..];
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for &mut R {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl<R: BufRead + ?Sized> BufRead for &mut R {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            (**self).fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            (**self).consume(amt)
+        }
+    }
+
+    impl<R: Read + ?Sized> Read for Box<R> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            (**self).read(buf)
+        }
+    }
+
+    impl<R: BufRead + ?Sized> BufRead for Box<R> {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            (**self).fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            (**self).consume(amt)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::{decompress_to_vec, DecompressBuilder, DecompressReader};
+
+    /// A source that fails after returning its data.
+    #[cfg(feature = "xz-rust")]
+    struct Failing<'a>(&'a [u8]);
+
+    #[cfg(feature = "xz-rust")]
+    impl Read for Failing<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let count = self.0.read(buf)?;
+            if count == 0 {
+                return Err(Error::new(ErrorKind::Other, "source failed"));
+            }
+            Ok(count)
+        }
+    }
+
+    #[cfg(feature = "xz-rust")]
+    impl BufRead for Failing<'_> {
+        fn fill_buf(&mut self) -> Result<&[u8]> {
+            if self.0.is_empty() {
+                return Err(Error::new(ErrorKind::Other, "source failed"));
+            }
+            Ok(self.0)
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.0.consume(amt)
+        }
+    }
+
+    #[test]
+    fn slice() {
+        let mut input = &b"abcdef"[..];
+        let mut buf = [0; 2];
+        input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ab");
+        assert_eq!(input.fill_buf().unwrap(), b"cdef");
+        input.consume(1);
+        let mut out = Vec::new();
+        assert_eq!(input.read_to_end(&mut out).unwrap(), 3);
+        assert_eq!(out, b"def");
+        assert_eq!(
+            input.read_exact(&mut buf).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn decode() {
+        let mut expected = Vec::new();
+        std::io::Read::read_to_end(
+            &mut GzDecoder::new(&include_bytes!("../fixtures/large.gz")[..]),
+            &mut expected,
+        )
+        .unwrap();
+        let inputs: &[&[u8]] = &[
+            #[cfg(feature = "xz-rust")]
+            include_bytes!("../fixtures/large.xz"),
+            #[cfg(feature = "zstd-rust")]
+            include_bytes!("../fixtures/large.zst"),
+        ];
+        for input in inputs {
+            let mut output = Vec::new();
+            DecompressReader::new(*input)
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            assert_eq!(output, expected);
+            assert_eq!(
+                decompress_to_vec(input, &DecompressBuilder::new()).unwrap(),
+                expected
+            );
+
+            // trailing data
+            let mut data = input.to_vec();
+            data.push(0);
+            let err = DecompressReader::new(&data[..])
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        // source errors pass through lzma-rust2
+        #[cfg(feature = "xz-rust")]
+        {
+            let input = include_bytes!("../fixtures/large.xz");
+            let err = DecompressReader::new(Failing(&input[..input.len() / 2]))
+                .unwrap()
+                .read_to_end(&mut Vec::new())
+                .unwrap_err();
+            assert_eq!(err.to_string(), "source failed");
+        }
+
+        // uncompressed
+        let mut output = Vec::new();
+        DecompressBuilder::none()
+            .uncompressed(true)
+            .build(&expected[..])
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, expected);
+    }
+}
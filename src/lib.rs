@@ -12,48 +12,98 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Without the std feature, readers work on core + alloc and use the I/O
+// traits in crate::io.  Writers, threads, and the C backends need std.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+// bzip2-rust is the same crate with a pure-Rust libbzip2, so the bzip2
+// code works unchanged with either
+#[cfg(all(feature = "bzip2-rust", not(feature = "bzip2")))]
+extern crate bzip2_rust as bzip2;
+
+use bytes::Bytes;
+use core::fmt;
+use core::mem;
 use enum_dispatch::enum_dispatch;
-use std::fmt;
-use std::io::{self, BufRead, ErrorKind, Read};
 
+use crate::io::{BufRead, ErrorKind, Read};
+
+mod chunks;
 mod config;
+mod counter;
 mod error;
 mod format;
+pub mod io;
+mod limits;
+mod members;
+mod oneshot;
 mod peek;
-#[cfg(test)]
+mod progress;
+#[cfg(feature = "std")]
+mod size;
+#[cfg(all(test, feature = "std"))]
 mod tests;
+#[cfg(feature = "std")]
+mod threaded;
+#[cfg(feature = "std")]
+mod writer;
 
+pub use self::chunks::*;
 pub use self::config::*;
 pub use self::error::*;
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+pub use self::format::bzip2::Bzip2Stream;
+#[cfg(feature = "gzip")]
+pub use self::format::gzip::GzipHeader;
+#[cfg(all(feature = "std", any(feature = "xz", feature = "xz-rust")))]
+pub use self::format::xz_list::*;
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+pub use self::format::zstd_common::ZstdFrameHeader;
+#[cfg(all(feature = "std", any(feature = "zstd", feature = "zstd-rust")))]
+pub use self::format::zstd_list::*;
+pub use self::members::*;
+pub use self::oneshot::*;
 pub use self::peek::*;
+pub use self::progress::{Progress, ProgressInterval};
+#[cfg(feature = "std")]
+pub use self::size::*;
+#[cfg(feature = "std")]
+pub use self::threaded::*;
+#[cfg(feature = "std")]
+pub use self::writer::*;
 
+use self::counter::Counter;
 use self::format::*;
 
 #[non_exhaustive]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CompressionFormat {
     Uncompressed,
-    #[cfg(feature = "bzip2")]
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
     Bzip2,
     #[cfg(feature = "gzip")]
     Gzip,
-    #[cfg(feature = "xz")]
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
     Xz,
-    #[cfg(feature = "zstd")]
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
     Zstd,
 }
 
 #[enum_dispatch]
 #[derive(Debug)]
+// built once per input, so the size disparity doesn't matter
+#[allow(clippy::large_enum_variant)]
 enum Format<'a, R: BufRead> {
     Uncompressed(UncompressedReader<'a, R>),
-    #[cfg(feature = "bzip2")]
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
     Bzip2(Bzip2Reader<R>),
     #[cfg(feature = "gzip")]
     Gzip(GzipReader<R>),
-    #[cfg(feature = "xz")]
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
     Xz(XzReader<R>),
-    #[cfg(feature = "zstd")]
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
     Zstd(ZstdReader<'a, R>),
 }
 
@@ -61,6 +111,10 @@ enum Format<'a, R: BufRead> {
 pub struct DecompressReader<'a, R: BufRead> {
     config: DecompressBuilder,
     reader: Format<'a, R>,
+    cache: DecoderCache<'a, R>,
+    counter: Counter,
+    // for next_chunk() from formats without an output buffer
+    chunk_buf: ChunkBuffer,
 }
 
 /// Format-sniffing decompressor
@@ -73,39 +127,31 @@ impl<'a, R: BufRead> DecompressReader<'a, R> {
         Self::new_full(source, DecompressBuilder::new())
     }
 
-    fn new_full(source: PeekReader<R>, config: DecompressBuilder) -> Result<Self> {
+    fn new_full(mut source: PeekReader<R>, config: DecompressBuilder) -> Result<Self> {
+        let mut cache = DecoderCache::new();
+        let counter = Counter::new(&config, source.consumed());
+        let format = detect(&mut source, &config)?;
         Ok(Self {
-            reader: Self::get_reader(source, &config)?,
+            reader: cache.take(format, source, &config)?,
             config,
+            cache,
+            counter,
+            chunk_buf: ChunkBuffer::default(),
         })
     }
 
-    fn get_reader(mut source: PeekReader<R>, config: &DecompressBuilder) -> Result<Format<'a, R>> {
-        #[cfg(feature = "bzip2")]
-        if config.bzip2 && Bzip2Reader::detect(&mut source)? {
-            return Ok(Bzip2Reader::new(source).into());
-        }
-
-        #[cfg(feature = "gzip")]
-        if config.gzip && GzipReader::detect(&mut source)? {
-            return Ok(GzipReader::new(source).into());
-        }
-
-        #[cfg(feature = "xz")]
-        if config.xz && XzReader::detect(&mut source)? {
-            return Ok(XzReader::new(source).into());
-        }
-
-        #[cfg(feature = "zstd")]
-        if config.zstd && ZstdReader::detect(&mut source)? {
-            return Ok(ZstdReader::new(source)?.into());
-        }
-
-        if config.uncompressed {
-            return Ok(UncompressedReader::new(source).into());
-        }
-
-        Err(DecompressError::UnrecognizedFormat)
+    /// Start decompressing a new source, and return the old one.  The
+    /// reader keeps one idle decoder per format, so decoding many inputs
+    /// with the same reader avoids reallocating decoder state and
+    /// buffers.  If the new source can't be decoded, the reader is left
+    /// unchanged.  total_in() and total_out() restart from zero.
+    pub fn reset(&mut self, source: R) -> Result<PeekReader<R>> {
+        let mut source = PeekReader::new(source);
+        let format = detect(&mut source, &self.config)?;
+        let reader = self.cache.take(format, source, &self.config)?;
+        let old = mem::replace(&mut self.reader, reader);
+        self.counter = Counter::new(&self.config, 0);
+        Ok(self.cache.put(old))
     }
 
     pub fn into_inner(self) -> PeekReader<R> {
@@ -119,29 +165,120 @@ impl<'a, R: BufRead> DecompressReader<'a, R> {
     pub fn format(&self) -> CompressionFormat {
         self.reader.as_primitive()
     }
+
+    /// Return the number of compressed bytes consumed from the source.
+    /// Bytes the reader has only peeked at aren't counted.  With parallel
+    /// decoding, this includes input queued for decoding whose output
+    /// hasn't been returned yet.
+    pub fn total_in(&self) -> u64 {
+        self.counter.total_in(self.reader.get_ref().consumed())
+    }
+
+    /// Return the number of decompressed bytes returned to the caller.
+    pub fn total_out(&self) -> u64 {
+        self.counter.total_out()
+    }
+
+    /// Return the structure of the bzip2 stream being decoded, as read so
+    /// far, or None if the input isn't bzip2.  Blocks are found by scanning
+    /// for the block magic number, which can occur by chance in compressed
+    /// data, so block CRCs are reconciled with the combined CRC at the end
+    /// of the stream.  Without bzip2_threads() > 1, block CRCs and the
+    /// combined CRC are only collected if enabled with
+    /// DecompressBuilder::bzip2_block_crcs().
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    pub fn bzip2_stream(&self) -> Option<&Bzip2Stream> {
+        match &self.reader {
+            Format::Bzip2(d) => d.stream(),
+            _ => None,
+        }
+    }
+
+    /// Return the header of the first gzip member, or None if the input
+    /// isn't gzip or the header is invalid.  Headers of later members in
+    /// concatenated input aren't parsed; use Members and
+    /// Member::gzip_header() to get them.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_header(&self) -> Option<&GzipHeader> {
+        match &self.reader {
+            Format::Gzip(d) => d.header(),
+            _ => None,
+        }
+    }
+
+    /// Return the header of the zstd frame being decoded, or None if the
+    /// input isn't zstd or the reader hasn't reached a frame.  If the
+    /// input starts with a regular frame, its header is available as soon
+    /// as the reader is created.
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    pub fn zstd_frame_header(&self) -> Option<&ZstdFrameHeader> {
+        match &self.reader {
+            Format::Zstd(d) => d.frame_header(),
+            _ => None,
+        }
+    }
 }
 
-impl<R: BufRead> Read for DecompressReader<'_, R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // enum_dispatch doesn't support supertraits
-        // https://gitlab.com/antonok/enum_dispatch/-/issues/56
+impl<'a, R: BufRead> DecompressReader<'a, R> {
+    /// Return the next chunk of decompressed data, or None at the end of
+    /// the compressed data.  Where the decompressor buffers its output
+    /// internally, the buffer is handed out without copying.
+    pub fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
         use Format::*;
-        let count = match &mut self.reader {
-            Uncompressed(d) => d.read(buf)?,
-            #[cfg(feature = "bzip2")]
-            Bzip2(d) => d.read(buf)?,
+        let chunk = match &mut self.reader {
+            Uncompressed(d) => self.chunk_buf.read(d)?,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Bzip2(d) => self.chunk_buf.read(d)?,
             #[cfg(feature = "gzip")]
-            Gzip(d) => d.read(buf)?,
+            Gzip(d) => self.chunk_buf.read(d)?,
             #[cfg(feature = "xz")]
-            Xz(d) => d.read(buf)?,
-            #[cfg(feature = "zstd")]
-            Zstd(d) => d.read(buf)?,
+            Xz(d) => d.next_chunk()?,
+            #[cfg(all(feature = "xz-rust", not(feature = "xz")))]
+            Xz(d) => self.chunk_buf.read(d)?,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Zstd(d) => d.next_chunk()?,
         };
-        if count == 0
-            && !buf.is_empty()
-            && self.format() != CompressionFormat::Uncompressed
-            && !self.config.trailing_data
-        {
+        match &chunk {
+            Some(chunk) => {
+                self.check_limits(chunk.len())?;
+                self.advance(chunk.len());
+            }
+            None => {
+                self.check_trailing_data()?;
+                self.report(true);
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Return an iterator over chunks of decompressed data.
+    pub fn chunks(&mut self) -> Chunks<'_, 'a, R> {
+        Chunks::new(self)
+    }
+
+    /// Fail if returning this many more decompressed bytes would exceed a
+    /// configured limit.
+    fn check_limits(&self, count: usize) -> Result<()> {
+        self.counter.check(self.reader.get_ref().consumed(), count)
+    }
+
+    /// Count decompressed bytes returned to the caller.
+    fn advance(&mut self, count: usize) {
+        self.counter.advance(count);
+        self.report(false);
+    }
+
+    fn report(&mut self, done: bool) {
+        self.counter.report(
+            self.reader.get_ref().consumed(),
+            self.reader.member(),
+            self.format(),
+            done,
+        );
+    }
+
+    fn check_trailing_data(&mut self) -> io::Result<()> {
+        if self.format() != CompressionFormat::Uncompressed && !self.config.trailing_data {
             // Decompressors stop reading as soon as they encounter the
             // compression trailer, so they don't notice trailing data,
             // which indicates something wrong with the input.  Look for
@@ -153,38 +290,109 @@ impl<R: BufRead> Read for DecompressReader<'_, R> {
                 ));
             }
         }
+        Ok(())
+    }
+}
+
+// enum_dispatch doesn't support supertraits, so we can't dispatch Read
+// and BufRead through FormatReader
+// https://gitlab.com/antonok/enum_dispatch/-/issues/56
+macro_rules! dispatch {
+    ($reader:expr, $d:ident => $expr:expr) => {
+        match $reader {
+            Format::Uncompressed($d) => $expr,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Format::Bzip2($d) => $expr,
+            #[cfg(feature = "gzip")]
+            Format::Gzip($d) => $expr,
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            Format::Xz($d) => $expr,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Format::Zstd($d) => $expr,
+        }
+    };
+}
+
+impl<R: BufRead> Read for DecompressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // decompress at most one byte past the size limit
+        let len = self.counter.read_len(buf.len());
+        let buf = &mut buf[..len];
+        let count = dispatch!(&mut self.reader, d => d.read(buf)?);
+        if count == 0 && !buf.is_empty() {
+            self.check_trailing_data()?;
+            self.report(true);
+        } else {
+            self.check_limits(count)?;
+            self.advance(count);
+        }
         Ok(count)
     }
 }
 
+impl<R: BufRead> BufRead for DecompressReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let len = dispatch!(&mut self.reader, d => d.fill_buf()?.len());
+        if len == 0 {
+            self.check_trailing_data()?;
+            self.report(true);
+            return Ok(&[]);
+        }
+        self.check_limits(len)?;
+        // the data is already buffered
+        Ok(dispatch!(&mut self.reader, d => d.fill_buf()?))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        dispatch!(&mut self.reader, d => d.consume(amt));
+        self.advance(amt);
+    }
+}
+
+impl<R: BufRead> Read for Format<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, d => d.read(buf))
+    }
+}
+
+impl<R: BufRead> BufRead for Format<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        dispatch!(self, d => d.fill_buf())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        dispatch!(self, d => d.consume(amt))
+    }
+}
+
 impl<R: BufRead> Format<'_, R> {
     fn as_primitive(&self) -> CompressionFormat {
         use CompressionFormat::*;
         match self {
             Self::Uncompressed(_) => Uncompressed,
-            #[cfg(feature = "bzip2")]
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
             Self::Bzip2(_) => Bzip2,
             #[cfg(feature = "gzip")]
             Self::Gzip(_) => Gzip,
-            #[cfg(feature = "xz")]
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
             Self::Xz(_) => Xz,
-            #[cfg(feature = "zstd")]
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
             Self::Zstd(_) => Zstd,
         }
     }
 }
 
 impl fmt::Display for CompressionFormat {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> std::result::Result<(), fmt::Error> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> core::result::Result<(), fmt::Error> {
         let name = match self {
             Self::Uncompressed => "uncompressed",
-            #[cfg(feature = "bzip2")]
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
             Self::Bzip2 => "bzip2",
             #[cfg(feature = "gzip")]
             Self::Gzip => "gzip",
-            #[cfg(feature = "xz")]
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
             Self::Xz => "xz",
-            #[cfg(feature = "zstd")]
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
             Self::Zstd => "zstd",
         };
         write!(f, "{}", name)
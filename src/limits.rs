@@ -0,0 +1,62 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Output limits from DecompressBuilder::max_size() and max_ratio().  The
+// callers count their own input and output and check them here before
+// handing out more data.
+
+use crate::{DecompressBuilder, DecompressError, Result};
+
+/// Output always allowed by DecompressBuilder::max_ratio().
+const RATIO_LIMIT_SLACK: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Limits {
+    max_size: Option<u64>,
+    max_ratio: Option<u64>,
+}
+
+impl Limits {
+    pub(crate) fn new(config: &DecompressBuilder) -> Self {
+        Self {
+            max_size: config.max_size,
+            max_ratio: config.max_ratio,
+        }
+    }
+
+    /// Fail if total_out bytes of output from total_in bytes of input
+    /// would exceed a limit.
+    pub(crate) fn check(&self, total_in: u64, total_out: u64) -> Result<()> {
+        if let Some(limit) = self.max_size {
+            if total_out > limit {
+                return Err(DecompressError::SizeLimitExceeded { limit });
+            }
+        }
+        if let Some(limit) = self.max_ratio {
+            if total_out > RATIO_LIMIT_SLACK && total_out > total_in.saturating_mul(limit) {
+                return Err(DecompressError::RatioLimitExceeded { limit });
+            }
+        }
+        Ok(())
+    }
+
+    /// Return how many more bytes can be output after total_out without
+    /// exceeding the size limit.
+    pub(crate) fn remaining(&self, total_out: u64) -> usize {
+        match self.max_size {
+            Some(limit) => usize::try_from(limit.saturating_sub(total_out)).unwrap_or(usize::MAX),
+            None => usize::MAX,
+        }
+    }
+}
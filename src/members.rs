@@ -0,0 +1,312 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Member-by-member decoding of concatenated input.  Each member gets its
+// own format reader, configured to stop at the end of the member, and
+// the readers' decoder state is reused between members.  Compressed
+// offsets come from the bytes consumed from the PeekReader, so they
+// aren't affected by its lookahead.
+
+use core::fmt;
+
+use crate::counter::Counter;
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+use crate::format::zstd_common;
+use crate::format::{detect, DecoderCache};
+use crate::io::{self, BufRead, ErrorKind, Read};
+#[cfg(feature = "gzip")]
+use crate::GzipHeader;
+use crate::{CompressionFormat, DecompressBuilder, Format, FormatReader, PeekReader, Result};
+
+/// Iterator over the members of a concatenated input.  Call
+/// next_member() to start each member.  The limits and progress callback
+/// from the config apply to the output of all members together, and the
+/// final progress report is sent when next_member() reaches the end of
+/// the input.
+pub struct Members<'a, R: BufRead> {
+    config: DecompressBuilder,
+    cache: DecoderCache<'a, R>,
+    // the source between members
+    source: Option<PeekReader<R>>,
+    current: Option<Current<'a, R>>,
+    // format of the first member
+    format: Option<CompressionFormat>,
+    index: u64,
+    // counts the output of all members together
+    counter: Counter,
+}
+
+struct Current<'a, R: BufRead> {
+    reader: Format<'a, R>,
+    offset: u64,
+    skippable: bool,
+    uncompressed_len: u64,
+    // set when the member has been read to the end
+    compressed_len: Option<u64>,
+}
+
+impl<R: BufRead + fmt::Debug> fmt::Debug for Members<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Members")
+            .field("format", &self.format)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, R: BufRead> Members<'a, R> {
+    pub(crate) fn new(source: PeekReader<R>, mut config: DecompressBuilder) -> Self {
+        config.single_member = true;
+        Self {
+            counter: Counter::new(&config, source.consumed()),
+            config,
+            cache: DecoderCache::new(),
+            source: Some(source),
+            current: None,
+            format: None,
+            index: 0,
+        }
+    }
+
+    /// Start the next member and return it, or None at the end of the
+    /// input.  Any unread data in the previous member is decompressed and
+    /// skipped, subject to the limits from the config.  All
+    /// members must have the format of the first one; anything else is
+    /// trailing data, which is an error unless allowed by the config.
+    pub fn next_member(&mut self) -> Result<Option<Member<'_, 'a, R>>> {
+        if self.current.is_some() {
+            // skip the rest of the member through Member, so it's counted
+            // and limited like the data the caller reads
+            let mut member = Member { members: self };
+            loop {
+                let count = member.fill_buf()?.len();
+                if count == 0 {
+                    break;
+                }
+                member.consume(count);
+            }
+            let current = self.current.take().unwrap();
+            self.source = Some(self.cache.put(current.reader));
+            self.index += 1;
+        }
+        let source = self.source.as_mut().unwrap();
+        if source.peek(1)?.is_empty() {
+            self.report_done();
+            return Ok(None);
+        }
+        let format = match (detect(source, &self.config), self.format) {
+            (Ok(format), None) => format,
+            (Ok(format), Some(first)) if format == first => format,
+            (Err(e), None) => return Err(e),
+            _ if self.config.trailing_data => {
+                self.report_done();
+                return Ok(None);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "found trailing data after compressed stream",
+                )
+                .into())
+            }
+        };
+        self.format = Some(format);
+        let skippable = is_skippable(format, source.peek(4)?);
+        let offset = source.consumed();
+        let source = self.source.take().unwrap();
+        self.current = Some(Current {
+            reader: self.cache.take(format, source, &self.config)?,
+            offset,
+            skippable,
+            uncompressed_len: 0,
+            compressed_len: None,
+        });
+        Ok(Some(Member { members: self }))
+    }
+
+    /// Return the number of compressed bytes consumed from the source,
+    /// across all members.
+    pub fn total_in(&self) -> u64 {
+        self.counter.total_in(self.consumed())
+    }
+
+    /// Return the number of decompressed bytes returned to the caller,
+    /// across all members, including any skipped by next_member().
+    pub fn total_out(&self) -> u64 {
+        self.counter.total_out()
+    }
+
+    fn consumed(&self) -> u64 {
+        match &self.current {
+            Some(current) => current.reader.get_ref().consumed(),
+            None => self.source.as_ref().unwrap().consumed(),
+        }
+    }
+
+    fn report_done(&mut self) {
+        // nothing to report for empty input
+        if let Some(format) = self.format {
+            let consumed = self.consumed();
+            // the last member, since index has moved past it
+            self.counter.report(consumed, self.index - 1, format, true);
+        }
+    }
+
+    /// Return the source.  If a member is in progress, the source is
+    /// positioned within it.
+    pub fn into_inner(self) -> PeekReader<R> {
+        match self.current {
+            Some(current) => current.reader.into_inner(),
+            None => self.source.unwrap(),
+        }
+    }
+}
+
+/// A member of a concatenated input, which reads the member's
+/// decompressed data.
+pub struct Member<'m, 'a, R: BufRead> {
+    members: &'m mut Members<'a, R>,
+}
+
+impl<R: BufRead + fmt::Debug> fmt::Debug for Member<'_, '_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Member")
+            .field("index", &self.index())
+            .field("format", &self.format())
+            .field("offset", &self.offset())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, R: BufRead> Member<'_, 'a, R> {
+    fn current(&self) -> &Current<'a, R> {
+        self.members.current.as_ref().unwrap()
+    }
+
+    fn current_mut(&mut self) -> &mut Current<'a, R> {
+        self.members.current.as_mut().unwrap()
+    }
+
+    /// Index of the member in the input, starting from 0.
+    pub fn index(&self) -> u64 {
+        self.members.index
+    }
+
+    pub fn format(&self) -> CompressionFormat {
+        self.current().reader.as_primitive()
+    }
+
+    /// Offset of the member from the start of the input.
+    pub fn offset(&self) -> u64 {
+        self.current().offset
+    }
+
+    /// Whether the member is a zstd skippable frame, which has no
+    /// decompressed data.
+    pub fn is_skippable(&self) -> bool {
+        self.current().skippable
+    }
+
+    /// Compressed length of the member, including any xz Stream Padding
+    /// after it, once the member has been read to the end.
+    pub fn compressed_len(&self) -> Option<u64> {
+        self.current().compressed_len
+    }
+
+    /// Decompressed length of the member, once the member has been read
+    /// to the end.
+    pub fn uncompressed_len(&self) -> Option<u64> {
+        let current = self.current();
+        current.compressed_len.map(|_| current.uncompressed_len)
+    }
+
+    /// Fail if returning this many more decompressed bytes would exceed a
+    /// configured limit.
+    fn check_limits(&self, count: usize) -> Result<()> {
+        self.members.counter.check(self.members.consumed(), count)
+    }
+
+    /// Count decompressed bytes returned to the caller.
+    fn advance(&mut self, count: usize) {
+        self.current_mut().uncompressed_len += count as u64;
+        let consumed = self.members.consumed();
+        let format = self.format();
+        let members = &mut *self.members;
+        members.counter.advance(count);
+        members
+            .counter
+            .report(consumed, members.index, format, false);
+    }
+
+    /// Return the header of the member, or None if the input isn't gzip or
+    /// the header is invalid.
+    #[cfg(feature = "gzip")]
+    pub fn gzip_header(&self) -> Option<&GzipHeader> {
+        match &self.current().reader {
+            Format::Gzip(d) => d.header(),
+            _ => None,
+        }
+    }
+
+    fn finish(&mut self) {
+        let current = self.current_mut();
+        if current.compressed_len.is_none() {
+            current.compressed_len = Some(current.reader.get_mut().consumed() - current.offset);
+        }
+    }
+}
+
+impl<R: BufRead> Read for Member<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // decompress at most one byte past the size limit
+        let len = self.members.counter.read_len(buf.len());
+        let buf = &mut buf[..len];
+        let count = self.current_mut().reader.read(buf)?;
+        if count == 0 && !buf.is_empty() {
+            self.finish();
+        } else {
+            self.check_limits(count)?;
+            self.advance(count);
+        }
+        Ok(count)
+    }
+}
+
+impl<R: BufRead> BufRead for Member<'_, '_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let len = self.current_mut().reader.fill_buf()?.len();
+        if len == 0 {
+            self.finish();
+        } else {
+            self.check_limits(len)?;
+        }
+        // the data is already buffered
+        self.current_mut().reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.current_mut().reader.consume(amt);
+        self.advance(amt);
+    }
+}
+
+// arguments are unused if zstd is compiled out
+#[allow(unused_variables)]
+fn is_skippable(format: CompressionFormat, peek: &[u8]) -> bool {
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    if format == CompressionFormat::Zstd {
+        return zstd_common::is_skippable(peek);
+    }
+    false
+}
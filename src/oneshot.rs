@@ -0,0 +1,83 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// One-shot decompression of in-memory input.  A slice is already a
+// BufRead, so it doesn't need a BufReader, though it's still wrapped in a
+// PeekReader for format detection.  We use the format's size metadata, if
+// any, to allocate the output up front.
+
+use alloc::vec::Vec;
+
+use crate::format::size_hint;
+use crate::io::{ErrorKind, Read};
+use crate::{CompressionFormat, DecompressBuilder, DecompressError, Result};
+
+/// Upper bound on preallocation, in case the size metadata is corrupt.
+const MAX_PREALLOCATION: u64 = 1 << 30;
+
+/// Upper bound on preallocation as a multiple of the input size, so a few
+/// bytes of untrusted metadata can't force a large allocation.
+const MAX_PREALLOCATION_RATIO: u64 = 16;
+
+/// Decompress an in-memory input into a new Vec.
+pub fn decompress_to_vec(input: &[u8], config: &DecompressBuilder) -> Result<Vec<u8>> {
+    let mut reader = config.build(input)?;
+    let mut output = Vec::with_capacity(capacity(input, reader.format(), config));
+    reader.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// Output capacity to preallocate, from the size metadata but within the
+/// configured limits and a multiple of the input size.
+pub(crate) fn capacity(
+    input: &[u8],
+    format: CompressionFormat,
+    config: &DecompressBuilder,
+) -> usize {
+    size_hint(format, input)
+        .unwrap_or(0)
+        .min(MAX_PREALLOCATION)
+        .min((input.len() as u64).saturating_mul(MAX_PREALLOCATION_RATIO))
+        .min(config.max_size.unwrap_or(u64::MAX)) as usize
+}
+
+/// Decompress an in-memory input into the provided buffer and return the
+/// number of bytes written.  Fails with DecompressError::OutputTooSmall if
+/// the decompressed data doesn't fit.
+pub fn decompress_to_slice(
+    input: &[u8],
+    output: &mut [u8],
+    config: &DecompressBuilder,
+) -> Result<usize> {
+    let mut reader = config.build(input)?;
+    let mut pos = 0;
+    loop {
+        let ret = if pos < output.len() {
+            reader.read(&mut output[pos..])
+        } else {
+            // check for EOF
+            match reader.read(&mut [0]) {
+                Ok(0) => Ok(0),
+                Ok(_) => return Err(DecompressError::OutputTooSmall),
+                Err(e) => Err(e),
+            }
+        };
+        match ret {
+            Ok(0) => return Ok(pos),
+            Ok(count) => pos += count,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -17,13 +17,19 @@
 // only guarantees to return one byte.  For simplicity, we implement this
 // as a thin wrapper around BufReader.
 
+use alloc::vec::Vec;
 use bytes::{Buf, BytesMut};
-use std::io::{self, BufRead, Read, Seek, SeekFrom};
+#[cfg(feature = "std")]
+use std::io::{Seek, SeekFrom};
+
+use crate::io::{self, BufRead, Read};
 
 #[derive(Debug)]
 pub struct PeekReader<R: BufRead> {
     source: R,
     buf: BytesMut,
+    // bytes read or consumed so far
+    consumed: u64,
 }
 
 impl<R: BufRead> PeekReader<R> {
@@ -31,6 +37,7 @@ impl<R: BufRead> PeekReader<R> {
         Self {
             source,
             buf: BytesMut::new(),
+            consumed: 0,
         }
     }
 
@@ -38,6 +45,7 @@ impl<R: BufRead> PeekReader<R> {
         Self {
             source,
             buf: BytesMut::from(buf.as_ref()),
+            consumed: 0,
         }
     }
 
@@ -61,6 +69,11 @@ impl<R: BufRead> PeekReader<R> {
         Ok(&self.buf[..self.buf.len().min(amt)])
     }
 
+    /// Number of bytes read or consumed so far, not counting peeked bytes.
+    pub(crate) fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
     pub fn into_parts(self) -> (Vec<u8>, R) {
         (self.buf.into(), self.source)
     }
@@ -71,15 +84,19 @@ impl<R: BufRead> Read for PeekReader<R> {
         if buf.is_empty() {
             return Ok(0);
         }
-        if self.buf.has_remaining() {
+        let count = if self.buf.has_remaining() {
             let count = buf.len().min(self.buf.remaining());
             self.buf.copy_to_slice(&mut buf[..count]);
-            return Ok(count);
-        }
-        self.source.read(buf)
+            count
+        } else {
+            self.source.read(buf)?
+        };
+        self.consumed += count as u64;
+        Ok(count)
     }
 }
 
+#[cfg(feature = "std")]
 impl<R: BufRead + Seek> Seek for PeekReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.buf.clear();
@@ -103,10 +120,11 @@ impl<R: BufRead> BufRead for PeekReader<R> {
         } else {
             self.source.consume(amt);
         }
+        self.consumed += amt as u64;
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use std::io::{BufReader, Cursor};
@@ -121,8 +139,7 @@ mod tests {
     }
 
     fn read_bytes(mut read: impl Read, amt: usize) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(amt);
-        buf.resize(amt, 0);
+        let mut buf = vec![0; amt];
         let amt = read.read(&mut buf).unwrap();
         buf.truncate(amt);
         buf
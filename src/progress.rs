@@ -0,0 +1,139 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Progress reporting from DecompressReader and Members.  They check
+// whether a report is due whenever they return data, so reports are never
+// more frequent than the caller's reads.
+
+use alloc::sync::Arc;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::CompressionFormat;
+
+/// How often to report progress.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProgressInterval {
+    /// Every time this many more decompressed bytes have been returned.
+    Bytes(u64),
+    /// At most once per interval.
+    #[cfg(feature = "std")]
+    Time(Duration),
+}
+
+/// Position of a DecompressReader or Members, passed to the progress
+/// callback.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Compressed bytes consumed, as from DecompressReader::total_in().
+    pub compressed: u64,
+    /// Decompressed bytes returned, as from DecompressReader::total_out().
+    pub decompressed: u64,
+    /// Index of the member being decoded, from 0: the bzip2 or xz stream,
+    /// or the zstd frame, counting skippable frames.  Always 0 for gzip,
+    /// which is decoded one member at a time, except with Members, where
+    /// this is the index of the Member.
+    pub member: u64,
+    pub format: CompressionFormat,
+    /// Whether the reader has reached the end of the input.  This is the
+    /// last report.
+    pub done: bool,
+}
+
+type ProgressCallback = dyn Fn(&Progress) + Send + Sync;
+
+/// Progress callback and interval from the config.
+#[derive(Clone)]
+pub(crate) struct ProgressHandler {
+    callback: Arc<ProgressCallback>,
+    interval: ProgressInterval,
+}
+
+impl ProgressHandler {
+    pub(crate) fn new<F: Fn(&Progress) + Send + Sync + 'static>(
+        interval: ProgressInterval,
+        callback: F,
+    ) -> Self {
+        Self {
+            callback: Arc::new(callback),
+            interval,
+        }
+    }
+}
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressHandler")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Tracks when the next report is due.
+#[derive(Debug)]
+pub(crate) struct ProgressState {
+    handler: ProgressHandler,
+    next_bytes: u64,
+    #[cfg(feature = "std")]
+    last_report: Instant,
+    // the final report has been sent
+    finished: bool,
+}
+
+impl ProgressState {
+    // ProgressInterval has one variant without std
+    #[allow(clippy::infallible_destructuring_match)]
+    pub(crate) fn new(handler: &ProgressHandler) -> Self {
+        let next_bytes = match handler.interval {
+            ProgressInterval::Bytes(bytes) => bytes,
+            #[cfg(feature = "std")]
+            ProgressInterval::Time(_) => 0,
+        };
+        Self {
+            handler: handler.clone(),
+            next_bytes,
+            #[cfg(feature = "std")]
+            last_report: Instant::now(),
+            finished: false,
+        }
+    }
+
+    /// Report progress if a report is due.
+    pub(crate) fn update(&mut self, progress: &Progress) {
+        if self.finished {
+            return;
+        }
+        let due = progress.done
+            || match self.handler.interval {
+                ProgressInterval::Bytes(_) => progress.decompressed >= self.next_bytes,
+                #[cfg(feature = "std")]
+                ProgressInterval::Time(interval) => self.last_report.elapsed() >= interval,
+            };
+        if !due {
+            return;
+        }
+        (self.handler.callback)(progress);
+        self.finished = progress.done;
+        match self.handler.interval {
+            ProgressInterval::Bytes(bytes) => {
+                self.next_bytes = progress.decompressed.saturating_add(bytes.max(1))
+            }
+            #[cfg(feature = "std")]
+            ProgressInterval::Time(_) => self.last_report = Instant::now(),
+        }
+    }
+}
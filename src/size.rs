@@ -0,0 +1,91 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Decompressed size of a seekable input, from the format's metadata.
+// Unlike size_hint(), this can seek to metadata at the end of the input
+// and walk the xz index and zstd frames without reading all the data.
+
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::format::detect;
+#[cfg(any(feature = "xz", feature = "xz-rust"))]
+use crate::format::xz_list::xz_uncompressed_size;
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+use crate::format::zstd_list::zstd_list;
+use crate::{CompressionFormat, DecompressBuilder, PeekReader, Result};
+
+/// Decompressed size of an input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct UncompressedSize {
+    pub size: u64,
+    /// Whether the size is exact.  gzip only records the size of the last
+    /// member, modulo 2^32, so gzip sizes are estimates.
+    pub exact: bool,
+}
+
+/// Get the decompressed size of the input between the source's current
+/// position and its end, from the format's metadata and without
+/// decompressing.  The starting position is restored afterward.  Returns
+/// None if the format doesn't record the size, as with bzip2 or zstd
+/// frames without a content size, or if the recorded sizes add up to more
+/// than u64::MAX.  Fails with
+/// DecompressError::UnrecognizedFormat if the format isn't enabled in the
+/// config.  lz4 isn't supported, since the crate has no lz4 decoder.
+pub fn uncompressed_size<R: Read + Seek>(
+    source: &mut R,
+    config: &DecompressBuilder,
+) -> Result<Option<UncompressedSize>> {
+    let start = source.stream_position()?;
+    let ret = detect(&mut PeekReader::new(BufReader::new(&mut *source)), config)
+        .and_then(|format| read_size(source, start, format));
+    source.seek(SeekFrom::Start(start))?;
+    ret
+}
+
+fn read_size<R: Read + Seek>(
+    source: &mut R,
+    start: u64,
+    format: CompressionFormat,
+) -> Result<Option<UncompressedSize>> {
+    use CompressionFormat::*;
+    let len = source.seek(SeekFrom::End(0))? - start;
+    source.seek(SeekFrom::Start(start))?;
+    let size = match format {
+        Uncompressed => Some(len),
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+        Bzip2 => None,
+        #[cfg(feature = "gzip")]
+        Gzip => {
+            // header plus trailer
+            if len < 18 {
+                return Ok(None);
+            }
+            let mut isize = [0; 4];
+            source.seek(SeekFrom::End(-4))?;
+            source.read_exact(&mut isize)?;
+            return Ok(Some(UncompressedSize {
+                size: u32::from_le_bytes(isize).into(),
+                exact: false,
+            }));
+        }
+        #[cfg(any(feature = "xz", feature = "xz-rust"))]
+        Xz => xz_uncompressed_size(source)?,
+        #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+        Zstd => zstd_list(source)?.iter().try_fold(0u64, |total, frame| {
+            total.checked_add(frame.header.content_size?)
+        }),
+    };
+    Ok(size.map(|size| UncompressedSize { size, exact: true }))
+}
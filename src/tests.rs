@@ -19,7 +19,7 @@ use flate2::read::GzDecoder;
 use lazy_static::lazy_static;
 use maplit::hashmap;
 use std::collections::HashMap;
-use std::io::{BufReader, Cursor, Read};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
 
 use crate::*;
 
@@ -59,45 +59,1304 @@ fn uncompressed() {
             .read_to_end(&mut output)
             .unwrap();
         assert_eq!(output, uncompressed);
+        assert_eq!(
+            write_decode(DecompressBuilder::new().uncompressed(true), &uncompressed).unwrap(),
+            uncompressed
+        );
     }
+
+    // consecutive chunks share an allocation
+    let uncompressed = get_expected("text");
+    let mut reader = DecompressBuilder::new()
+        .uncompressed(true)
+        .build(BufReader::with_capacity(10, &*uncompressed))
+        .unwrap();
+    let first = reader.next_chunk().unwrap().unwrap();
+    let second = reader.next_chunk().unwrap().unwrap();
+    assert_eq!(first.as_ptr().wrapping_add(first.len()), second.as_ptr());
 }
 
 #[test]
-#[cfg(feature = "bzip2")]
+#[cfg(feature = "stream")]
+fn stream() {
+    let uncompressed = get_expected("large");
+    let mut reader = DecompressBuilder::new()
+        .uncompressed(true)
+        .build(BufReader::with_capacity(32, &*uncompressed))
+        .unwrap();
+    let mut output = Vec::new();
+    for chunk in futures::executor::block_on_stream(reader.chunks()) {
+        output.extend(chunk.unwrap());
+    }
+    assert_eq!(output, uncompressed);
+}
+
+#[test]
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
 fn bzip2() {
-    test_set(CompressionFormat::Bzip2, &*BZIP2_FIXTURES);
+    test_set(CompressionFormat::Bzip2, &BZIP2_FIXTURES);
     // multiple streams may be concatenated; pbzip2 does this
-    test_concatenated_inputs(&*BZIP2_FIXTURES);
+    test_concatenated_inputs(&BZIP2_FIXTURES);
+
+    // corrupt data
+    let mut corrupt = BZIP2_FIXTURES.get("text").unwrap().to_vec();
+    let len = corrupt.len();
+    corrupt[len / 2] ^= 0x40;
+    let builder = DecompressBuilder::new();
+    assert_eq!(
+        read_decode(&builder, &corrupt).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+        std::io::Error::from(write_decode(&builder, &corrupt).unwrap_err()).kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+fn bzip2_parallel() {
+    let mut builder = DecompressBuilder::new();
+    builder.bzip2_threads(4);
+    // the fixtures each have a single block
+    for name in ["text", "random", "large"] {
+        let mut output = Vec::new();
+        builder
+            .build(BufReader::with_capacity(
+                32,
+                *BZIP2_FIXTURES.get(name).unwrap(),
+            ))
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, get_expected(name));
+    }
+    // test the underlying reader one byte at a time
+    small_decode(
+        Bzip2Reader::with_state(
+            small_decode_make(BZIP2_FIXTURES.get("random").unwrap()),
+            &builder,
+            None,
+        )
+        .unwrap(),
+        &get_expected("random"),
+    );
+
+    // several streams of several blocks, plus an empty stream
+    let expected = pseudo_random_text(400_000);
+    let mut input = Vec::new();
+    for data in [&expected[..], &[], &expected[..1000], &expected] {
+        let mut encoder = ::bzip2::write::BzEncoder::new(Vec::new(), ::bzip2::Compression::new(1));
+        encoder.write_all(data).unwrap();
+        input.extend(encoder.finish().unwrap());
+    }
+    let expected = [&expected[..], &expected[..1000], &expected].concat();
+    for decode in [read_decode, bufread_decode, chunk_decode] {
+        assert_eq!(decode(&builder, &input).unwrap(), expected);
+        // truncated
+        decode(&builder, &input[..input.len() - 1]).unwrap_err();
+        // trailing data
+        decode(&builder, &[&input[..], b"abc"].concat()).unwrap_err();
+        // corrupt block
+        let mut corrupt = input.clone();
+        corrupt[input.len() / 3] ^= 0x40;
+        decode(&builder, &corrupt).unwrap_err();
+        // corrupt stream CRC
+        let mut corrupt = input.clone();
+        corrupt[input.len() - 2] ^= 0x01;
+        decode(&builder, &corrupt).unwrap_err();
+    }
+}
+
+#[test]
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+fn bzip2_stream() {
+    // several blocks at level 1
+    let data = pseudo_random_text(250_000);
+    let mut input = BZIP2_FIXTURES.get("text").unwrap().to_vec();
+    let mut encoder = ::bzip2::write::BzEncoder::new(Vec::new(), ::bzip2::Compression::new(1));
+    encoder.write_all(&data).unwrap();
+    input.extend(encoder.finish().unwrap());
+    let text = get_expected("text");
+
+    for threads in [1, 4] {
+        let mut reader = DecompressBuilder::new()
+            .bzip2_threads(threads)
+            .bzip2_block_crcs(true)
+            .build(&input[..])
+            .unwrap();
+        let mut output = vec![0; text.len()];
+        reader.read_exact(&mut output).unwrap();
+        let stream = reader.bzip2_stream().unwrap();
+        assert_eq!(stream.index, 0);
+        assert_eq!(stream.level, 9);
+        assert_eq!(stream.blocks(), 1);
+        reader.read_to_end(&mut output).unwrap();
+        let stream = reader.bzip2_stream().unwrap();
+        assert_eq!(stream.index, 1);
+        assert_eq!(stream.level, 1);
+        assert_eq!(stream.blocks(), 3);
+        let combined = stream
+            .block_crcs
+            .iter()
+            .fold(0u32, |crc, block| crc.rotate_left(1) ^ block);
+        assert_eq!(stream.combined_crc, Some(combined));
+    }
+
+    // serial decoder without CRCs
+    let mut reader = DecompressBuilder::new().build(&input[..]).unwrap();
+    reader.read_to_end(&mut Vec::new()).unwrap();
+    let stream = reader.bzip2_stream().unwrap();
+    assert_eq!(stream.index, 1);
+    assert_eq!(stream.level, 1);
+    assert_eq!(stream.blocks(), 0);
+    assert_eq!(stream.combined_crc, None);
+
+    // other formats
+    let reader = DecompressBuilder::new()
+        .uncompressed(true)
+        .build(&b"abc"[..])
+        .unwrap();
+    assert_eq!(reader.bzip2_stream(), None);
 }
 
 #[test]
 #[cfg(feature = "gzip")]
 fn gzip() {
-    test_set(CompressionFormat::Gzip, &*GZIP_FIXTURES);
+    test_set(CompressionFormat::Gzip, &GZIP_FIXTURES);
+
+    // header metadata
+    let mut encoder = flate2::GzBuilder::new()
+        .filename("hello.txt")
+        .comment("greeting")
+        .mtime(1234567890)
+        .operating_system(3)
+        .extra(&b"AB\x03\x00xyzCD\x00\x00"[..])
+        .write(Vec::new(), flate2::Compression::best());
+    encoder.write_all(b"hello").unwrap();
+    let input = encoder.finish().unwrap();
+    let mut reader = DecompressReader::new(BufReader::with_capacity(4, &*input)).unwrap();
+    let header = reader.gzip_header().unwrap();
+    assert_eq!(header.filename(), Some(&b"hello.txt"[..]));
+    assert_eq!(header.comment(), Some(&b"greeting"[..]));
+    assert_eq!(header.mtime(), 1234567890);
+    assert_eq!(header.os(), 3);
+    assert_eq!(header.xfl(), 2);
+    assert_eq!(
+        header.extra_subfields().collect::<Vec<_>>(),
+        vec![(*b"AB", &b"xyz"[..]), (*b"CD", &b""[..])]
+    );
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).unwrap();
+    assert_eq!(output, b"hello");
+    assert_eq!(
+        reader.gzip_header().unwrap().filename(),
+        Some(&b"hello.txt"[..])
+    );
+
+    // truncated header
+    let mut reader = DecompressReader::new(&input[..16]).unwrap();
+    assert!(reader.gzip_header().is_none());
+    reader.read_to_end(&mut output).unwrap_err();
+
+    // no optional fields
+    let reader = DecompressReader::new(*GZIP_FIXTURES.get("text").unwrap()).unwrap();
+    let header = reader.gzip_header().unwrap();
+    assert_eq!(header.extra(), None);
+    assert_eq!(header.extra_subfields().count(), 0);
+    assert_eq!(header.comment(), None);
 }
 
 #[test]
-#[cfg(feature = "xz")]
+#[cfg(any(feature = "xz", feature = "xz-rust"))]
 fn xz() {
-    test_set(CompressionFormat::Xz, &*XZ_FIXTURES);
+    test_set(CompressionFormat::Xz, &XZ_FIXTURES);
+    // multiple streams may be concatenated
+    test_concatenated_inputs(&XZ_FIXTURES);
     // test the underlying reader one byte at a time
     small_decode(
-        XzReader::new(small_decode_make(XZ_FIXTURES.get("random").unwrap())),
+        XzReader::with_state(
+            small_decode_make(XZ_FIXTURES.get("random").unwrap()),
+            &DecompressBuilder::new(),
+            None,
+        )
+        .unwrap(),
         &get_expected("random"),
     );
+
+    // Stream Padding between and after streams
+    let stream = *XZ_FIXTURES.get("random").unwrap();
+    let expected = get_expected("random");
+    let padded = [stream, &[0; 8], stream, &[0; 4]].concat();
+    test_case(
+        "padded random",
+        &padded,
+        &[&expected[..], &expected].concat(),
+    );
+    assert_eq!(
+        format::size_hint(CompressionFormat::Xz, &padded),
+        Some(2 * expected.len() as u64)
+    );
+
+    // padding that isn't a multiple of 4
+    let is_padding_error = |e: DecompressError| matches!(e, DecompressError::XzStreamPadding);
+    for input in [
+        [stream, &[0; 5], stream].concat(),
+        [stream, &[0; 3]].concat(),
+    ] {
+        let mut output = Vec::new();
+        let err = DecompressReader::new(BufReader::with_capacity(32, &*input))
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap_err();
+        assert!(is_padding_error(err.into()));
+        let err = write_decode(&DecompressBuilder::new(), &input).unwrap_err();
+        assert!(is_padding_error(err));
+    }
+    // but short padding before trailing data is just trailing data
+    let input = [stream, &[0; 3], b"abc"].concat();
+    let mut reader = DecompressBuilder::new()
+        .trailing_data(true)
+        .build(BufReader::with_capacity(32, &*input))
+        .unwrap();
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).unwrap();
+    assert_eq!(output, expected);
+    let mut remainder = Vec::new();
+    reader.into_inner().read_to_end(&mut remainder).unwrap();
+    assert_eq!(&remainder, b"\0\0\0abc");
 }
 
 #[test]
-#[cfg(feature = "zstd")]
+#[cfg(any(feature = "xz", feature = "xz-rust"))]
+fn xz_listing() {
+    let text = XZ_FIXTURES.get("text").unwrap();
+    let large = XZ_FIXTURES.get("large").unwrap();
+    // two streams with padding, at a nonzero offset
+    let input = [&b"junk"[..], text, &[0; 8], large, &[0; 4]].concat();
+    let mut source = Cursor::new(&input);
+    source.set_position(4);
+    let info = xz_list(&mut source).unwrap();
+    assert_eq!(source.position(), 4);
+    assert_eq!(info.streams.len(), 2);
+    let (first, second) = (&info.streams[0], &info.streams[1]);
+    assert_eq!(first.offset, 0);
+    assert_eq!(first.compressed_size, text.len() as u64);
+    assert_eq!(first.padding, 8);
+    assert_eq!(second.offset, text.len() as u64 + 8);
+    assert_eq!(second.compressed_size, large.len() as u64);
+    assert_eq!(second.padding, 4);
+    assert_eq!(second.check, XzCheck::Crc64);
+    assert_eq!(second.blocks.len(), 1);
+    let block = &second.blocks[0];
+    assert_eq!(block.offset, second.offset + 12);
+    assert_eq!(block.compressed_size, 245);
+    assert_eq!(block.uncompressed_size, 1 << 20);
+    assert_eq!(block.filters.len(), 1);
+    assert_eq!(block.filters[0].name(), Some("lzma2"));
+    assert_eq!(
+        info.uncompressed_size(),
+        (get_expected("text").len() + get_expected("large").len()) as u64
+    );
+
+    // not xz
+    assert!(matches!(
+        xz_list(&mut Cursor::new(b"hello world")).unwrap_err(),
+        DecompressError::UnrecognizedFormat
+    ));
+    // corrupt index CRC
+    let mut corrupt = large.to_vec();
+    let len = corrupt.len();
+    corrupt[len - 13] ^= 1;
+    xz_list(&mut Cursor::new(&corrupt)).unwrap_err();
+    assert_eq!(format::size_hint(CompressionFormat::Xz, &corrupt), None);
+    // truncated
+    xz_list(&mut Cursor::new(&large[..large.len() - 1])).unwrap_err();
+    // sizes that overflow, within a stream and across streams
+    let max = u64::MAX >> 1;
+    let valid = xz_stream_with_sizes(&[max, 1]);
+    assert_eq!(
+        xz_list(&mut Cursor::new(&valid))
+            .unwrap()
+            .uncompressed_size(),
+        max + 1
+    );
+    for input in [
+        xz_stream_with_sizes(&[max, max, max]),
+        [&valid[..], &valid[..]].concat(),
+    ] {
+        assert_eq!(
+            std::io::Error::from(xz_list(&mut Cursor::new(&input)).unwrap_err()).kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "xz")]
+fn xz_threaded() {
+    // multi-block streams with sizes in the block headers
+    let expected = [get_expected("large"), get_expected("random")].concat();
+    let stream = xz2::stream::MtStreamBuilder::new()
+        .threads(2)
+        .block_size(64 * 1024)
+        .encoder()
+        .unwrap();
+    let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+    encoder.write_all(&expected).unwrap();
+    let stream = encoder.finish().unwrap();
+    let input = [&stream[..], &[0; 4], &stream].concat();
+    let expected = [&expected[..], &expected].concat();
+
+    // without the xz-threaded feature and liblzma >= 5.4, these fall back
+    // to single-threaded
+    for builder in [
+        DecompressBuilder::new().xz_threads(4),
+        // too little memory for threads
+        DecompressBuilder::new().xz_threads(4).xz_thread_memlimit(1),
+    ] {
+        let mut reader = builder
+            .build(BufReader::with_capacity(32, &*input))
+            .unwrap();
+        match &reader.reader {
+            Format::Xz(d) => assert_eq!(d.is_threaded(), cfg!(xz_threaded)),
+            _ => unreachable!(),
+        }
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, expected);
+        // truncated
+        builder
+            .build(&input[..input.len() - 1])
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        // trailing data
+        builder
+            .build(&*[&input[..], b"abc"].concat())
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+    }
+}
+
+#[test]
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
 fn zstd() {
-    test_set(CompressionFormat::Zstd, &*ZSTD_FIXTURES);
+    test_set(CompressionFormat::Zstd, &ZSTD_FIXTURES);
     // test with multiple frames
-    test_concatenated_inputs(&*ZSTD_FIXTURES);
+    test_concatenated_inputs(&ZSTD_FIXTURES);
+    // test the underlying reader one byte at a time
+    small_decode(
+        ZstdReader::with_state(
+            small_decode_make(ZSTD_FIXTURES.get("random").unwrap()),
+            &DecompressBuilder::new(),
+            None,
+        )
+        .unwrap(),
+        &get_expected("random"),
+    );
+    // corrupt checksum
+    let mut corrupt = ZSTD_FIXTURES.get("text").unwrap().to_vec();
+    *corrupt.last_mut().unwrap() ^= 0x01;
+    read_decode(&DecompressBuilder::new(), &corrupt).unwrap_err();
+}
+
+#[test]
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+fn zstd_frame_header() {
+    let text = ZSTD_FIXTURES.get("text").unwrap();
+    let large = ZSTD_FIXTURES.get("large").unwrap();
+    let skippable = [&0x184d2a50u32.to_le_bytes()[..], &[2, 0, 0, 0, 0, 0]].concat();
+    let input = [&skippable[..], text, large].concat();
+    let mut builders = vec![DecompressBuilder::new()];
+    #[cfg(feature = "zstd")]
+    builders.push(DecompressBuilder::new().zstd_threads(4).clone());
+    for builder in builders {
+        // not yet known after a skippable frame
+        let mut reader = builder.build(&input[..]).unwrap();
+        assert_eq!(reader.zstd_frame_header(), None);
+        let mut buf = vec![0; get_expected("text").len()];
+        reader.read_exact(&mut buf).unwrap();
+        let header = reader.zstd_frame_header().unwrap();
+        assert_eq!(header.content_size, Some(buf.len() as u64));
+        assert_eq!(header.window_size, buf.len() as u64);
+        assert_eq!(header.dictionary_id, None);
+        assert!(header.checksum);
+        assert!(header.single_segment);
+        reader.read_to_end(&mut buf).unwrap();
+        let header = reader.zstd_frame_header().unwrap();
+        assert_eq!(header.content_size, Some(1 << 20));
+        assert_eq!(header.window_size, 1 << 20);
+
+        // available immediately
+        let reader = builder.build(&large[..]).unwrap();
+        assert_eq!(
+            reader.zstd_frame_header().unwrap().content_size,
+            Some(1 << 20)
+        );
+    }
+
+    // other formats
+    let reader = DecompressBuilder::new()
+        .uncompressed(true)
+        .build(&b"abc"[..])
+        .unwrap();
+    assert_eq!(reader.zstd_frame_header(), None);
+}
+
+#[test]
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+fn zstd_listing() {
+    let text = ZSTD_FIXTURES.get("text").unwrap();
+    let large = ZSTD_FIXTURES.get("large").unwrap();
+    let skippable = [&0x184d2a5fu32.to_le_bytes()[..], &[1, 0, 0, 0, 0]].concat();
+    // a skippable frame between two frames, at a nonzero offset
+    let input = [&b"junk"[..], text, &skippable, large].concat();
+    let mut source = Cursor::new(&input);
+    source.set_position(4);
+    let frames = zstd_list(&mut source).unwrap();
+    assert_eq!(source.position(), 4);
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].offset, 0);
+    assert_eq!(frames[0].compressed_size, text.len() as u64);
+    assert_eq!(
+        frames[0].header.content_size,
+        Some(get_expected("text").len() as u64)
+    );
+    assert_eq!(frames[1].offset, (text.len() + skippable.len()) as u64);
+    assert_eq!(frames[1].compressed_size, large.len() as u64);
+    assert_eq!(frames[1].header.window_size, 1 << 20);
+
+    // trailing data
+    let input = [&text[..], b"abc"].concat();
+    assert_eq!(zstd_list(&mut Cursor::new(&input)).unwrap().len(), 1);
+    // not zstd
+    assert!(matches!(
+        zstd_list(&mut Cursor::new(b"hello world")).unwrap_err(),
+        DecompressError::UnrecognizedFormat
+    ));
+    // truncated
+    zstd_list(&mut Cursor::new(&large[..large.len() - 1])).unwrap_err();
+    zstd_list(&mut Cursor::new(&skippable[..7])).unwrap_err();
+    for len in [5, 8] {
+        match zstd_list(&mut Cursor::new(&large[..len])).unwrap_err() {
+            DecompressError::Io { source } => {
+                assert_eq!(source.kind(), std::io::ErrorKind::UnexpectedEof)
+            }
+            e => panic!("unexpected error {e}"),
+        }
+    }
+}
+
+#[test]
+#[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+fn zstd_skippable_frames() {
+    use std::sync::{Arc, Mutex};
+
+    let skippable = |variant: u32, payload: &[u8]| {
+        let magic = (0x184d2a50 | variant).to_le_bytes();
+        let size = (payload.len() as u32).to_le_bytes();
+        [&magic[..], &size, payload].concat()
+    };
+    let input = [
+        &skippable(0, b"signature")[..],
+        ZSTD_FIXTURES.get("text").unwrap(),
+        &skippable(15, b""),
+        ZSTD_FIXTURES.get("large").unwrap(),
+        &skippable(3, &[7; 100]),
+    ]
+    .concat();
+    let expected_frames = vec![
+        (0, b"signature".to_vec()),
+        (15, Vec::new()),
+        (3, vec![7; 100]),
+    ];
+    let expected = [get_expected("text"), get_expected("large")].concat();
+
+    let mut builders = vec![DecompressBuilder::new()];
+    #[cfg(feature = "zstd")]
+    builders.push(DecompressBuilder::new().zstd_threads(4).clone());
+    for builder in builders {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = builder.clone();
+        let frames_ = frames.clone();
+        builder.zstd_skippable_frames(move |variant, payload| {
+            frames_.lock().unwrap().push((variant, payload.to_vec()))
+        });
+        type Decode = fn(&DecompressBuilder, &[u8]) -> std::io::Result<Vec<u8>>;
+        let decoders: [Decode; 4] = [
+            read_decode,
+            bufread_decode,
+            chunk_decode,
+            |builder, input| Ok(write_decode(builder, input)?),
+        ];
+        for decode in decoders {
+            assert_eq!(decode(&builder, &input).unwrap(), expected);
+            assert_eq!(*frames.lock().unwrap(), expected_frames);
+            frames.lock().unwrap().clear();
+        }
+
+        // one member at a time
+        let mut members = builder.members(&input[..]);
+        let mut member = members.next_member().unwrap().unwrap();
+        assert!(member.is_skippable());
+        assert!(frames.lock().unwrap().is_empty());
+        member.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(frames.lock().unwrap().len(), 1);
+        while members.next_member().unwrap().is_some() {}
+        assert_eq!(*frames.lock().unwrap(), expected_frames);
+
+        // truncated payload
+        let truncated = &input[..input.len() - 1];
+        read_decode(&builder, truncated).unwrap_err();
+        write_decode(&builder, truncated).unwrap_err();
+    }
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn zstd_parallel() {
+    let mut builder = DecompressBuilder::new();
+    builder.zstd_threads(4);
     // test the underlying reader one byte at a time
     small_decode(
-        ZstdReader::new(small_decode_make(ZSTD_FIXTURES.get("random").unwrap())).unwrap(),
+        ZstdReader::with_state(
+            small_decode_make(ZSTD_FIXTURES.get("random").unwrap()),
+            &builder,
+            None,
+        )
+        .unwrap(),
         &get_expected("random"),
     );
+
+    // pzstd-style frames, with a skippable frame before each one
+    let chunk = [get_expected("text"), get_expected("random")].concat();
+    let mut compressor = ::zstd::bulk::Compressor::new(3).unwrap();
+    compressor
+        .set_parameter(::zstd::stream::raw::CParameter::ChecksumFlag(true))
+        .unwrap();
+    let mut input = Vec::new();
+    let mut expected = Vec::new();
+    for _ in 0..20 {
+        let frame = compressor.compress(&chunk).unwrap();
+        input.extend(0x184d2a50u32.to_le_bytes());
+        input.extend(4u32.to_le_bytes());
+        input.extend((frame.len() as u32).to_le_bytes());
+        input.extend(frame);
+        expected.extend(&chunk);
+    }
+    // a frame without a content size, and one too large to decode in
+    // parallel, which are decoded serially
+    input.extend(::zstd::stream::encode_all(&*chunk, 3).unwrap());
+    expected.extend(&chunk);
+    let large = get_expected("large").repeat(20);
+    input.extend(compressor.compress(&large).unwrap());
+    expected.extend(&large);
+    // and more parallel frames
+    input.extend(compressor.compress(&chunk).unwrap());
+    expected.extend(&chunk);
+
+    for decode in [read_decode, bufread_decode, chunk_decode] {
+        assert_eq!(decode(&builder, &input).unwrap(), expected);
+        // truncated
+        decode(&builder, &input[..input.len() - 1]).unwrap_err();
+        // trailing data
+        decode(&builder, &[&input[..], b"abc"].concat()).unwrap_err();
+        // corrupt frame
+        let mut corrupt = input.clone();
+        corrupt[input.len() / 4] ^= 0x40;
+        decode(&builder, &corrupt).unwrap_err();
+    }
+}
+
+#[test]
+#[cfg(any(
+    all(feature = "bzip2", feature = "bzip2-rust"),
+    all(feature = "xz", feature = "xz-rust"),
+    all(feature = "zstd", feature = "zstd-rust")
+))]
+fn pure_rust_backends() {
+    // When both backends of a format are enabled, the C one is used
+    // everywhere else, so compare them directly.
+    let builder = DecompressBuilder::new();
+    for name in ["text", "random", "large"] {
+        let expected = get_expected(name);
+
+        #[cfg(all(feature = "bzip2", feature = "bzip2-rust"))]
+        {
+            let input = [*BZIP2_FIXTURES.get(name).unwrap(), &b"abc"[..]].concat();
+            let mut output = Vec::new();
+            let mut reader = ::bzip2_rust::bufread::BzDecoder::new(&*input);
+            reader.read_to_end(&mut output).unwrap();
+            assert_eq!(output, expected);
+            assert_eq!(reader.into_inner(), b"abc");
+        }
+
+        #[cfg(all(feature = "xz", feature = "xz-rust"))]
+        {
+            // concatenated streams with Stream Padding
+            let stream = *XZ_FIXTURES.get(name).unwrap();
+            let input = [stream, &[0; 4], stream, b"abc"].concat();
+            let expected = expected.repeat(2);
+            let c = XzReader::with_state(PeekReader::new(&*input), &builder, None).unwrap();
+            let rust =
+                format::xz_rust::XzReader::with_state(PeekReader::new(&*input), &builder, None)
+                    .unwrap();
+            assert_eq!(format_read(c), expected);
+            assert_eq!(format_read(rust), expected);
+            let input = &input[..input.len() - 3];
+            let c = XzWriter::new(Vec::new(), &builder).unwrap();
+            let rust = format::xz_rust::XzWriter::new(Vec::new(), &builder).unwrap();
+            assert_eq!(format_write(c, input), expected);
+            assert_eq!(format_write(rust, input), expected);
+        }
+
+        #[cfg(all(feature = "zstd", feature = "zstd-rust"))]
+        {
+            // concatenated frames with a skippable frame
+            let frame = *ZSTD_FIXTURES.get(name).unwrap();
+            let skippable = [&0x184d2a50u32.to_le_bytes()[..], &[1, 0, 0, 0, 0xff]].concat();
+            let input = [frame, &skippable, frame, b"abc"].concat();
+            let expected = expected.repeat(2);
+            let c = ZstdReader::with_state(PeekReader::new(&*input), &builder, None).unwrap();
+            let rust =
+                format::zstd_rust::ZstdReader::with_state(PeekReader::new(&*input), &builder, None)
+                    .unwrap();
+            assert_eq!(format_read(c), expected);
+            assert_eq!(format_read(rust), expected);
+            let input = &input[..input.len() - 3];
+            let c = ZstdWriter::new(Vec::new(), &builder).unwrap();
+            let rust = format::zstd_rust::ZstdWriter::new(Vec::new(), &builder).unwrap();
+            assert_eq!(format_write(c, input), expected);
+            assert_eq!(format_write(rust, input), expected);
+        }
+    }
+}
+
+#[test]
+#[cfg(all(feature = "gzip", any(feature = "xz", feature = "xz-rust")))]
+fn nested() {
+    // gzip-compressed xz
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(XZ_FIXTURES.get("text").unwrap()).unwrap();
+    let input = encoder.finish().unwrap();
+    let outer = DecompressReader::new(BufReader::with_capacity(32, &*input)).unwrap();
+    assert_eq!(outer.format(), CompressionFormat::Gzip);
+    let inner = DecompressReader::new(outer).unwrap();
+    assert_eq!(inner.format(), CompressionFormat::Xz);
+    let lines: Vec<String> = inner.lines().map(|l| l.unwrap()).collect();
+    let expected = String::from_utf8(get_expected("text")).unwrap();
+    assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn uncompressed_size() {
+    let size = |input: &[u8], builder: &DecompressBuilder| {
+        let input = [&b"junk"[..], input].concat();
+        let mut source = Cursor::new(&input);
+        source.set_position(4);
+        let ret = crate::uncompressed_size(&mut source, builder);
+        assert_eq!(source.position(), 4);
+        ret
+    };
+    let exact = |size| Some(UncompressedSize { size, exact: true });
+
+    let mut builder = DecompressBuilder::new();
+    builder.uncompressed(true);
+    assert_eq!(size(b"abc", &builder).unwrap(), exact(3));
+    assert!(matches!(
+        size(b"abc", &DecompressBuilder::new()).unwrap_err(),
+        DecompressError::UnrecognizedFormat
+    ));
+
+    let text = get_expected("text").len() as u64;
+    let large = get_expected("large").len() as u64;
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    assert_eq!(
+        size(BZIP2_FIXTURES.get("text").unwrap(), &builder).unwrap(),
+        None
+    );
+    #[cfg(feature = "gzip")]
+    {
+        let input = [
+            *GZIP_FIXTURES.get("large").unwrap(),
+            GZIP_FIXTURES.get("text").unwrap(),
+        ]
+        .concat();
+        // only the last member
+        assert_eq!(
+            size(&input, &builder).unwrap(),
+            Some(UncompressedSize {
+                size: text,
+                exact: false
+            })
+        );
+    }
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    {
+        let input = [
+            *XZ_FIXTURES.get("large").unwrap(),
+            XZ_FIXTURES.get("text").unwrap(),
+        ]
+        .concat();
+        assert_eq!(size(&input, &builder).unwrap(), exact(large + text));
+        // block sizes that overflow
+        let max = u64::MAX >> 1;
+        let input = xz_stream_with_sizes(&[max, max, max]);
+        assert_eq!(size(&input, &builder).unwrap(), None);
+    }
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    {
+        let input = [
+            *ZSTD_FIXTURES.get("large").unwrap(),
+            ZSTD_FIXTURES.get("text").unwrap(),
+        ]
+        .concat();
+        assert_eq!(size(&input, &builder).unwrap(), exact(large + text));
+        // no content size
+        #[cfg(feature = "zstd")]
+        {
+            let mut encoder = ::zstd::stream::Encoder::new(Vec::new(), 3).unwrap();
+            encoder.include_contentsize(false).unwrap();
+            encoder.write_all(b"abc").unwrap();
+            assert_eq!(size(&encoder.finish().unwrap(), &builder).unwrap(), None);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn preallocation() {
+    let input = *GZIP_FIXTURES.get("large").unwrap();
+    let format = CompressionFormat::Gzip;
+    let large = get_expected("large").len();
+    assert_eq!(
+        oneshot::capacity(input, format, &DecompressBuilder::new()),
+        large.min(16 * input.len())
+    );
+    assert_eq!(
+        oneshot::capacity(input, format, DecompressBuilder::new().max_size(100)),
+        100
+    );
+    // ISIZE from a few bytes of input
+    let mut input = input.to_vec();
+    let len = input.len();
+    input[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        oneshot::capacity(&input[len - 30..], format, &DecompressBuilder::new()),
+        16 * 30
+    );
+}
+
+#[test]
+fn members() {
+    let text = get_expected("text");
+    let large = get_expected("large");
+    let check = |builder: &DecompressBuilder, input: &[u8], members: &[(&[u8], &[u8])]| {
+        let mut iter = builder.members(input);
+        let mut offset = 0;
+        for (i, (compressed, expected)) in members.iter().enumerate() {
+            let mut member = iter.next_member().unwrap().unwrap();
+            assert_eq!(member.index(), i as u64);
+            assert_eq!(member.offset(), offset);
+            assert_eq!(member.compressed_len(), None);
+            let mut buf = Vec::new();
+            member.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, *expected);
+            assert_eq!(member.compressed_len(), Some(compressed.len() as u64));
+            assert_eq!(member.uncompressed_len(), Some(expected.len() as u64));
+            offset += compressed.len() as u64;
+        }
+        assert!(iter.next_member().unwrap().is_none());
+        assert_eq!(offset, input.len() as u64);
+        assert_eq!(iter.total_in(), input.len() as u64);
+        assert_eq!(
+            iter.total_out(),
+            members.iter().map(|(_, e)| e.len() as u64).sum::<u64>()
+        );
+    };
+
+    let mut builders = vec![DecompressBuilder::new()];
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    builders.push(DecompressBuilder::new().bzip2_threads(4).clone());
+    #[cfg(feature = "zstd")]
+    builders.push(DecompressBuilder::new().zstd_threads(4).clone());
+    for builder in &builders {
+        let fixtures: &[&HashMap<&str, &[u8]>] = &[
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            &BZIP2_FIXTURES,
+            #[cfg(feature = "gzip")]
+            &GZIP_FIXTURES,
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            &XZ_FIXTURES,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            &ZSTD_FIXTURES,
+        ];
+        for fixture in fixtures {
+            let members = [
+                (*fixture.get("large").unwrap(), &large[..]),
+                (*fixture.get("text").unwrap(), &text[..]),
+                (*fixture.get("large").unwrap(), &large[..]),
+            ];
+            let input = members.map(|(c, _)| c).concat();
+            check(builder, &input, &members);
+        }
+        #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+        {
+            let skippable = [&0x184d2a50u32.to_le_bytes()[..], &[2, 0, 0, 0, 0, 0]].concat();
+            let text_zst = *ZSTD_FIXTURES.get("text").unwrap();
+            let members = [(&skippable[..], &b""[..]), (text_zst, &text[..])];
+            let input = members.map(|(c, _)| c).concat();
+            check(builder, &input, &members);
+            let mut iter = builder.members(&input[..]);
+            assert!(iter.next_member().unwrap().unwrap().is_skippable());
+            assert!(!iter.next_member().unwrap().unwrap().is_skippable());
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    {
+        let gz_text = *GZIP_FIXTURES.get("text").unwrap();
+        let gz_large = *GZIP_FIXTURES.get("large").unwrap();
+        let input = [gz_large, gz_text, b"abc"].concat();
+
+        // skip an unread member
+        let mut iter = DecompressBuilder::new().members(&input[..]);
+        let member = iter.next_member().unwrap().unwrap();
+        assert_eq!(member.format(), CompressionFormat::Gzip);
+        let mut member = iter.next_member().unwrap().unwrap();
+        assert_eq!(member.offset(), gz_large.len() as u64);
+        let mut buf = Vec::new();
+        member.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, text);
+        assert_eq!(iter.total_out(), (large.len() + text.len()) as u64);
+
+        // skipped members are limited too
+        let mut iter = DecompressBuilder::new().max_size(1000).members(&input[..]);
+        iter.next_member().unwrap().unwrap();
+        assert!(matches!(
+            iter.next_member().unwrap_err(),
+            DecompressError::SizeLimitExceeded { limit: 1000 }
+        ));
+
+        // trailing data
+        iter.next_member().unwrap_err();
+        let mut iter = DecompressBuilder::new()
+            .trailing_data(true)
+            .members(&input[..]);
+        assert!(iter.next_member().unwrap().is_some());
+        assert!(iter.next_member().unwrap().is_some());
+        assert!(iter.next_member().unwrap().is_none());
+        let mut rest = Vec::new();
+        iter.into_inner().read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"abc");
+
+        // per-member headers
+        let input = [&b"one"[..], b"two"]
+            .map(|name| {
+                let mut encoder = flate2::GzBuilder::new()
+                    .filename(name)
+                    .write(Vec::new(), flate2::Compression::best());
+                encoder.write_all(b"hello").unwrap();
+                encoder.finish().unwrap()
+            })
+            .concat();
+        let mut iter = DecompressBuilder::new().members(&input[..]);
+        for name in [&b"one"[..], b"two"] {
+            let member = iter.next_member().unwrap().unwrap();
+            assert_eq!(member.gzip_header().unwrap().filename(), Some(name));
+        }
+    }
+
+    // uncompressed
+    let mut iter = DecompressBuilder::new()
+        .uncompressed(true)
+        .members(&b"abc"[..]);
+    let mut member = iter.next_member().unwrap().unwrap();
+    assert_eq!(member.format(), CompressionFormat::Uncompressed);
+    let mut buf = Vec::new();
+    member.read_to_end(&mut buf).unwrap();
+    assert_eq!(member.compressed_len(), Some(3));
+    assert!(iter.next_member().unwrap().is_none());
+    // empty
+    assert!(DecompressBuilder::new()
+        .members(&b""[..])
+        .next_member()
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn byte_counts() {
+    let mut fixtures: Vec<(&[u8], Vec<u8>)> = vec![(b"abc", b"abc".to_vec())];
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    fixtures.push((BZIP2_FIXTURES.get("large").unwrap(), get_expected("large")));
+    #[cfg(feature = "gzip")]
+    fixtures.push((GZIP_FIXTURES.get("large").unwrap(), get_expected("large")));
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    fixtures.push((XZ_FIXTURES.get("large").unwrap(), get_expected("large")));
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    fixtures.push((ZSTD_FIXTURES.get("large").unwrap(), get_expected("large")));
+
+    let mut builder = DecompressBuilder::new();
+    builder.uncompressed(true);
+    for (input, expected) in fixtures {
+        let mut reader = builder.build(BufReader::with_capacity(32, input)).unwrap();
+        assert_eq!(reader.total_out(), 0);
+        // partial read
+        let mut buf = vec![0; 2];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.total_out(), 2);
+        assert!(reader.total_in() > 0 && reader.total_in() <= input.len() as u64);
+        // BufRead
+        let count = reader.fill_buf().unwrap().len().min(1);
+        reader.consume(count);
+        assert_eq!(reader.total_out(), 2 + count as u64);
+        // chunks
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            buf.extend(&chunk[..]);
+        }
+        assert_eq!(reader.total_in(), input.len() as u64);
+        assert_eq!(reader.total_out(), expected.len() as u64);
+
+        // reset
+        reader.reset(BufReader::with_capacity(32, input)).unwrap();
+        assert_eq!(reader.total_out(), 0);
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(reader.total_in(), input.len() as u64);
+        assert_eq!(reader.total_out(), expected.len() as u64);
+    }
+
+    // trailing data isn't counted
+    #[cfg(feature = "gzip")]
+    {
+        let text = *GZIP_FIXTURES.get("text").unwrap();
+        let input = [text, b"abc"].concat();
+        let mut reader = DecompressBuilder::new()
+            .trailing_data(true)
+            .build(&input[..])
+            .unwrap();
+        reader.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(reader.total_in(), text.len() as u64);
+    }
+}
+
+#[test]
+fn progress() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    let large = get_expected("large");
+    let mut fixtures: Vec<(Vec<u8>, u64)> = Vec::new();
+    #[cfg(feature = "gzip")]
+    fixtures.push((GZIP_FIXTURES.get("large").unwrap().to_vec(), 1));
+    // multi-member formats
+    let multi: &[&HashMap<&str, &[u8]>] = &[
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+        &BZIP2_FIXTURES,
+        #[cfg(any(feature = "xz", feature = "xz-rust"))]
+        &XZ_FIXTURES,
+        #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+        &ZSTD_FIXTURES,
+    ];
+    for cases in multi {
+        fixtures.push((cases.get("large").unwrap().repeat(3), 3));
+    }
+
+    for (input, members) in fixtures {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_ = reports.clone();
+        let mut builder = DecompressBuilder::new();
+        builder.progress(ProgressInterval::Bytes(100_000), move |progress| {
+            reports_.lock().unwrap().push(*progress)
+        });
+        let mut reader = builder.build(&input[..]).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output.len() as u64, large.len() as u64 * members);
+
+        let reports = reports.lock().unwrap();
+        // reads can cross several thresholds at once
+        assert!(reports.len() > 2);
+        assert!(reports.len() as u64 <= output.len() as u64 / 100_000 + 1);
+        for pair in reports.windows(2) {
+            assert!(pair[1].decompressed - pair[0].decompressed >= 100_000 || pair[1].done);
+            assert!(pair[1].compressed >= pair[0].compressed);
+            assert!(pair[1].member >= pair[0].member);
+        }
+        let last = reports.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.compressed, input.len() as u64);
+        assert_eq!(last.decompressed, output.len() as u64);
+        assert_eq!(last.member, members - 1);
+        assert_eq!(last.format, reader.format());
+        assert_eq!(reports.iter().filter(|r| r.done).count(), 1);
+
+        // every read
+        let count = Arc::new(Mutex::new(0));
+        let count_ = count.clone();
+        let mut reader = DecompressBuilder::new()
+            .progress(ProgressInterval::Time(Duration::ZERO), move |_| {
+                *count_.lock().unwrap() += 1
+            })
+            .build(&input[..])
+            .unwrap();
+        let mut buf = [0; 100];
+        reader.read_exact(&mut buf).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(*count.lock().unwrap(), 2);
+
+        // members
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_ = reports.clone();
+        let mut iter = DecompressBuilder::new()
+            .progress(ProgressInterval::Bytes(100_000), move |progress| {
+                reports_.lock().unwrap().push(*progress)
+            })
+            .members(&input[..]);
+        while let Some(mut member) = iter.next_member().unwrap() {
+            std::io::copy(&mut member, &mut std::io::sink()).unwrap();
+        }
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 2);
+        for pair in reports.windows(2) {
+            assert!(pair[1].decompressed - pair[0].decompressed >= 100_000 || pair[1].done);
+            assert!(pair[1].member >= pair[0].member);
+        }
+        let last = reports.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.compressed, input.len() as u64);
+        assert_eq!(last.decompressed, output.len() as u64);
+        assert_eq!(last.member, members - 1);
+        assert_eq!(reports.iter().filter(|r| r.done).count(), 1);
+    }
+}
+
+#[test]
+fn limits() {
+    let large = get_expected("large").len() as u64;
+    let fixtures: &[&HashMap<&str, &[u8]>] = &[
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+        &BZIP2_FIXTURES,
+        #[cfg(feature = "gzip")]
+        &GZIP_FIXTURES,
+        #[cfg(any(feature = "xz", feature = "xz-rust"))]
+        &XZ_FIXTURES,
+        #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+        &ZSTD_FIXTURES,
+    ];
+
+    let decode = |builder: &DecompressBuilder, input: &[u8]| {
+        let mut reader = builder.build(input).unwrap();
+        let mut buf = vec![0; 100_000];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(reader.total_out()),
+                Ok(_) => (),
+                Err(e) => {
+                    // nothing past the limit was returned
+                    if let Some(limit) = builder.max_size {
+                        assert!(reader.total_out() <= limit);
+                    }
+                    return Err(DecompressError::from(e));
+                }
+            }
+        }
+    };
+    for fixture in fixtures {
+        let input = *fixture.get("large").unwrap();
+        let mut builder = DecompressBuilder::new();
+        assert_eq!(decode(builder.max_size(large), input).unwrap(), large);
+        assert!(matches!(
+            decode(builder.max_size(large - 1), input).unwrap_err(),
+            DecompressError::SizeLimitExceeded { limit } if limit == large - 1
+        ));
+        assert!(matches!(
+            decode(builder.max_size(1000), input).unwrap_err(),
+            DecompressError::SizeLimitExceeded { limit: 1000 }
+        ));
+        // BufRead
+        let mut reader = builder.build(input).unwrap();
+        assert!(matches!(
+            reader.read_until(b'x', &mut Vec::new()).unwrap_err().into(),
+            DecompressError::SizeLimitExceeded { limit: 1000 }
+        ));
+        // chunks
+        let mut reader = builder.build(input).unwrap();
+        assert!(reader.chunks().any(|chunk| chunk.is_err()));
+
+        // the first MiB is always allowed
+        let mut builder = DecompressBuilder::new();
+        assert_eq!(decode(builder.max_ratio(1), input).unwrap(), large);
+    }
+
+    // ratio
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    {
+        let input = BZIP2_FIXTURES.get("large").unwrap().repeat(3);
+        let mut builder = DecompressBuilder::new();
+        assert_eq!(
+            decode(builder.max_ratio(1_000_000), &input).unwrap(),
+            3 * large
+        );
+        assert!(matches!(
+            decode(builder.max_ratio(1000), &input).unwrap_err(),
+            DecompressError::RatioLimitExceeded { limit: 1000 }
+        ));
+    }
+
+    // writer and members
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    {
+        let write = |builder: &DecompressBuilder, input: &[u8]| -> Result<u64> {
+            let mut writer = builder.build_writer(Vec::new());
+            if let Err(e) = writer.write_all(input) {
+                // output stopped at the limit
+                if let Some(limit) = builder.max_size {
+                    assert_eq!(writer.get_ref().len() as u64, limit);
+                }
+                return Err(e.into());
+            }
+            Ok(writer.finish()?.len() as u64)
+        };
+        let members = |builder: &DecompressBuilder, input: &[u8]| -> Result<u64> {
+            let mut iter = builder.members(input);
+            let mut total = 0;
+            while let Some(mut member) = iter.next_member()? {
+                total += std::io::copy(&mut member, &mut std::io::sink())?;
+            }
+            Ok(total)
+        };
+        let input = BZIP2_FIXTURES.get("large").unwrap().repeat(3);
+        for decode in [&write as &dyn Fn(&_, &_) -> _, &members] {
+            let mut builder = DecompressBuilder::new();
+            assert_eq!(
+                decode(builder.max_size(3 * large), &input).unwrap(),
+                3 * large
+            );
+            assert!(matches!(
+                decode(builder.max_size(large + 1000), &input).unwrap_err(),
+                DecompressError::SizeLimitExceeded { limit } if limit == large + 1000
+            ));
+            let mut builder = DecompressBuilder::new();
+            assert_eq!(
+                decode(builder.max_ratio(1_000_000), &input).unwrap(),
+                3 * large
+            );
+            assert!(matches!(
+                decode(builder.max_ratio(1000), &input).unwrap_err(),
+                DecompressError::RatioLimitExceeded { limit: 1000 }
+            ));
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "zstd")]
+fn writer_start_failure() {
+    // libzstd rejects the window limit when creating the decoder
+    let input = *ZSTD_FIXTURES.get("text").unwrap();
+    let mut writer = DecompressBuilder::new()
+        .zstd_window_log_max(100)
+        .build_writer(Vec::new());
+    for _ in 0..2 {
+        writer.write_all(input).unwrap_err();
+    }
+    assert_eq!(writer.format(), None);
+    assert!(writer.get_mut().is_empty());
+    writer.flush().unwrap();
+    writer.finish().unwrap_err();
+}
+
+#[test]
+fn memory_limits() {
+    let large = get_expected("large");
+
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    for threads in [1, 2] {
+        let input = *BZIP2_FIXTURES.get("large").unwrap();
+        let mut builder = DecompressBuilder::new();
+        builder.bzip2_small(true).bzip2_threads(threads);
+        let mut output = Vec::new();
+        builder
+            .build(input)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, large);
+        assert_eq!(write_decode(&builder, input).unwrap(), large);
+    }
+
+    // large.xz has a 64 MiB dictionary
+    #[cfg(feature = "xz")]
+    for threads in [1, 2] {
+        let input = *XZ_FIXTURES.get("large").unwrap();
+        let mut builder = DecompressBuilder::new();
+        builder.xz_memlimit(128 << 20);
+        #[cfg(feature = "xz-threaded")]
+        builder.xz_threads(threads);
+        let mut output = Vec::new();
+        builder
+            .build(input)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, large);
+        assert_eq!(write_decode(&builder, input).unwrap(), large);
+
+        builder.xz_memlimit(1 << 20);
+        let err = builder
+            .build(input)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            err.into(),
+            DecompressError::XzMemlimitExceeded { limit } if limit == 1 << 20
+        ));
+        assert!(matches!(
+            write_decode(&builder, input).unwrap_err(),
+            DecompressError::XzMemlimitExceeded { limit } if limit == 1 << 20
+        ));
+    }
+
+    // large.zst has a 1 MiB window
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    for threads in [1, 2] {
+        let input = *ZSTD_FIXTURES.get("large").unwrap();
+        let mut builder = DecompressBuilder::new();
+        builder.zstd_window_log_max(20).zstd_threads(threads);
+        let mut output = Vec::new();
+        builder
+            .build(input)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        assert_eq!(output, large);
+        assert_eq!(write_decode(&builder, input).unwrap(), large);
+
+        builder.zstd_window_log_max(19);
+        let err = builder
+            .build(input)
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(
+            err.into(),
+            DecompressError::ZstdWindowTooLarge {
+                window_size: 1048576,
+                window_log_max: 19
+            }
+        ));
+        assert!(matches!(
+            write_decode(&builder, input).unwrap_err(),
+            DecompressError::ZstdWindowTooLarge {
+                window_size: 1048576,
+                window_log_max: 19
+            }
+        ));
+    }
 }
 
 #[test]
@@ -108,10 +1367,93 @@ fn invalid() {
     ));
 }
 
+#[test]
+fn reset() {
+    let mut builder = DecompressBuilder::new();
+    builder.uncompressed(true).trailing_data(true);
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    builder.bzip2_threads(2);
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    builder.zstd_threads(2);
+
+    // input, expected output, and the input left over afterward
+    let mut cases = vec![(get_expected("text"), get_expected("text"), Vec::new())];
+    let fixtures = [
+        (
+            cfg!(any(feature = "bzip2", feature = "bzip2-rust")),
+            &*BZIP2_FIXTURES,
+        ),
+        (cfg!(feature = "gzip"), &*GZIP_FIXTURES),
+        (
+            cfg!(any(feature = "xz", feature = "xz-rust")),
+            &*XZ_FIXTURES,
+        ),
+        (
+            cfg!(any(feature = "zstd", feature = "zstd-rust")),
+            &*ZSTD_FIXTURES,
+        ),
+    ];
+    for (_, fixture) in fixtures.iter().filter(|(enabled, _)| *enabled) {
+        for name in ["text", "random"] {
+            let input = [fixture.get(name).unwrap(), &b"tail"[..]].concat();
+            cases.push((input, get_expected(name), b"tail".to_vec()));
+        }
+    }
+
+    // switch formats repeatedly, abandoning each input partway through
+    // on the second pass
+    let mut reader = builder.build(&b"initial"[..]).unwrap();
+    let mut leftover: Option<&[u8]> = Some(b"initial");
+    for partial in [false, true] {
+        for (input, expected, remainder) in &cases {
+            let mut old = reader.reset(input).unwrap();
+            if let Some(leftover) = leftover {
+                let mut buf = Vec::new();
+                old.read_to_end(&mut buf).unwrap();
+                assert_eq!(buf, leftover);
+            }
+            if partial {
+                let mut buf = [0; 100];
+                reader.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf[..], &expected[..100]);
+                leftover = None;
+            } else {
+                let mut output = Vec::new();
+                reader.read_to_end(&mut output).unwrap();
+                assert_eq!(&output, expected);
+                leftover = Some(remainder);
+            }
+        }
+    }
+
+    // a failed reset leaves the reader alone
+    if let Some((input, expected, _)) = cases.get(1) {
+        let mut reader = DecompressBuilder::new()
+            .trailing_data(true)
+            .build(&input[..])
+            .unwrap();
+        assert!(matches!(
+            reader.reset(&b"hello world"[..]).unwrap_err(),
+            DecompressError::UnrecognizedFormat
+        ));
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(&output, expected);
+    }
+}
+
 fn test_set(format: CompressionFormat, inputs: &HashMap<&str, &[u8]>) {
     api_test(format, inputs.get("large").unwrap(), &get_expected("large"));
     for (name, data) in inputs {
         test_case(name, data, &get_expected(name));
+        let hint = format::size_hint(format, data);
+        // bzip2 doesn't record the decompressed size
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+        if format == CompressionFormat::Bzip2 {
+            assert_eq!(hint, None);
+            continue;
+        }
+        assert_eq!(hint, Some(get_expected(name).len() as u64));
     }
 }
 
@@ -127,13 +1469,13 @@ fn api_test(format: CompressionFormat, input: &[u8], expected: &[u8]) {
     use CompressionFormat::*;
     match format {
         Uncompressed => unreachable!(),
-        #[cfg(feature = "bzip2")]
+        #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
         Bzip2 => builder.bzip2(true),
         #[cfg(feature = "gzip")]
         Gzip => builder.gzip(true),
-        #[cfg(feature = "xz")]
+        #[cfg(any(feature = "xz", feature = "xz-rust"))]
         Xz => builder.xz(true),
-        #[cfg(feature = "zstd")]
+        #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
         Zstd => builder.zstd(true),
     };
     let mut reader = builder
@@ -173,6 +1515,36 @@ fn api_test(format: CompressionFormat, input: &[u8], expected: &[u8]) {
     let (buf, mut reader) = reader.into_inner().into_parts();
     assert_eq!(&buf, &[]);
     reader.capacity();
+
+    // build_writer
+    let mut writer = builder.build_writer(Vec::new());
+    assert_eq!(writer.format(), None);
+    writer.write_all(&input).unwrap();
+    assert_eq!(writer.format(), Some(format));
+    assert_eq!(&writer.finish().unwrap(), expected);
+
+    // writer with algorithms disabled; the detection error persists
+    let mut writer = DecompressBuilder::none().build_writer(Vec::new());
+    for _ in 0..2 {
+        assert!(matches!(
+            writer.write_all(&input).unwrap_err().into(),
+            DecompressError::UnrecognizedFormat
+        ));
+    }
+    assert_eq!(writer.format(), None);
+    assert!(writer.get_ref().is_empty());
+    writer.flush().unwrap();
+    assert!(matches!(
+        writer.finish().unwrap_err(),
+        DecompressError::UnrecognizedFormat
+    ));
+    assert!(matches!(
+        DecompressBuilder::none()
+            .build_writer(Vec::new())
+            .finish()
+            .unwrap_err(),
+        DecompressError::UnrecognizedFormat
+    ));
 }
 
 /// test a format implementation
@@ -187,6 +1559,44 @@ fn test_case(name: &str, input: &[u8], expected: &[u8]) {
         .read_to_end(&mut output)
         .unwrap();
     assert_eq!(&output, expected);
+    assert_eq!(
+        &write_decode(&DecompressBuilder::new(), &input).unwrap(),
+        expected
+    );
+    assert_eq!(
+        &chunk_decode(&DecompressBuilder::new(), &input).unwrap(),
+        expected
+    );
+    assert_eq!(
+        &bufread_decode(&DecompressBuilder::new(), &input).unwrap(),
+        expected
+    );
+    assert_eq!(
+        &threaded_decode(&DecompressBuilder::new(), &input).unwrap(),
+        expected
+    );
+    assert_eq!(
+        &decompress_to_vec(&input, &DecompressBuilder::new()).unwrap(),
+        expected
+    );
+    let mut slice = vec![0; expected.len() + 1];
+    let count = decompress_to_slice(&input, &mut slice, &DecompressBuilder::new()).unwrap();
+    assert_eq!(&slice[..count], expected);
+    let count = decompress_to_slice(
+        &input,
+        &mut slice[..expected.len()],
+        &DecompressBuilder::new(),
+    )
+    .unwrap();
+    assert_eq!(count, expected.len());
+    assert!(matches!(
+        decompress_to_slice(
+            &input,
+            &mut slice[..expected.len() - 1],
+            &DecompressBuilder::new()
+        ),
+        Err(DecompressError::OutputTooSmall)
+    ));
 
     // drop last byte, make sure we notice
     output.clear();
@@ -194,6 +1604,11 @@ fn test_case(name: &str, input: &[u8], expected: &[u8]) {
         .unwrap()
         .read_to_end(&mut output)
         .unwrap_err();
+    write_decode(&DecompressBuilder::new(), &input[0..input.len() - 1]).unwrap_err();
+    chunk_decode(&DecompressBuilder::new(), &input[0..input.len() - 1]).unwrap_err();
+    bufread_decode(&DecompressBuilder::new(), &input[0..input.len() - 1]).unwrap_err();
+    threaded_decode(&DecompressBuilder::new(), &input[0..input.len() - 1]).unwrap_err();
+    decompress_to_vec(&input[0..input.len() - 1], &DecompressBuilder::new()).unwrap_err();
 
     // add trailing garbage, make sure we notice
     input.push(12);
@@ -202,6 +1617,11 @@ fn test_case(name: &str, input: &[u8], expected: &[u8]) {
         .unwrap()
         .read_to_end(&mut output)
         .unwrap_err();
+    write_decode(&DecompressBuilder::new(), &input).unwrap_err();
+    chunk_decode(&DecompressBuilder::new(), &input).unwrap_err();
+    bufread_decode(&DecompressBuilder::new(), &input).unwrap_err();
+    threaded_decode(&DecompressBuilder::new(), &input).unwrap_err();
+    decompress_to_vec(&input, &DecompressBuilder::new()).unwrap_err();
 
     // use concatenated mode, make sure we ignore trailing garbage
     output.clear();
@@ -214,6 +1634,149 @@ fn test_case(name: &str, input: &[u8], expected: &[u8]) {
     let mut remainder = Vec::new();
     reader.into_inner().read_to_end(&mut remainder).unwrap();
     assert_eq!(&remainder, &[12]);
+    assert_eq!(
+        &write_decode(DecompressBuilder::new().trailing_data(true), &input).unwrap(),
+        expected
+    );
+
+    // same with ThreadedDecompressReader
+    output.clear();
+    let mut reader = ThreadedDecompressReader::new(
+        DecompressBuilder::new()
+            .trailing_data(true)
+            .build(BufReader::with_capacity(32, Cursor::new(input.clone())))
+            .unwrap(),
+    );
+    reader.read_to_end(&mut output).unwrap();
+    assert_eq!(&output, expected);
+    let mut remainder = Vec::new();
+    reader
+        .into_inner()
+        .unwrap()
+        .read_to_end(&mut remainder)
+        .unwrap();
+    assert_eq!(&remainder, &[12]);
+}
+
+/// decompress with DecompressReader::chunks()
+fn chunk_decode(builder: &DecompressBuilder, input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut reader = builder.build(BufReader::with_capacity(32, input))?;
+    let mut output = Vec::new();
+    for chunk in reader.chunks() {
+        output.extend(chunk?);
+    }
+    Ok(output)
+}
+
+/// decompress with DecompressReader's Read implementation
+fn read_decode(builder: &DecompressBuilder, input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    builder
+        .build(BufReader::with_capacity(32, input))?
+        .read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// decompress with DecompressReader's BufRead implementation
+fn bufread_decode(builder: &DecompressBuilder, input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut reader = builder.build(BufReader::with_capacity(32, input))?;
+    let mut output = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        // consume partially to exercise buffer handling
+        let count = buf.len().min(1000);
+        output.extend(&buf[..count]);
+        reader.consume(count);
+    }
+    Ok(output)
+}
+
+/// decompress with ThreadedDecompressReader
+fn threaded_decode(builder: &DecompressBuilder, input: &[u8]) -> std::io::Result<Vec<u8>> {
+    let source = BufReader::with_capacity(32, Cursor::new(input.to_vec()));
+    let mut reader = ThreadedDecompressReader::new(builder.build(source)?);
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output)?;
+    Ok(output)
+}
+
+/// decompress with DecompressWriter, feeding the input in small chunks
+fn write_decode(builder: &DecompressBuilder, input: &[u8]) -> Result<Vec<u8>> {
+    let mut writer = builder.build_writer(Vec::new());
+    for chunk in input.chunks(7) {
+        writer.write_all(chunk)?;
+    }
+    writer.finish()
+}
+
+/// decompress with a FormatReader, and check that it stops at trailing
+/// data
+fn format_read<R: BufRead, T: Read + FormatReader<R>>(mut reader: T) -> Vec<u8> {
+    let mut output = Vec::new();
+    reader.read_to_end(&mut output).unwrap();
+    let mut remainder = Vec::new();
+    reader.into_inner().read_to_end(&mut remainder).unwrap();
+    assert_eq!(remainder, b"abc");
+    output
+}
+
+/// decompress with a FormatWriter, feeding the input in small chunks
+fn format_write<T: FormatWriter<Vec<u8>>>(mut writer: T, input: &[u8]) -> Vec<u8> {
+    for mut chunk in input.chunks(7) {
+        while !chunk.is_empty() {
+            let count = writer.write_compressed(chunk).unwrap();
+            assert!(count > 0);
+            chunk = &chunk[count..];
+        }
+    }
+    let (output, trailing) = writer.finish().unwrap();
+    assert!(!trailing);
+    output
+}
+
+/// Build an xz stream whose index records blocks with the specified
+/// uncompressed sizes.  The blocks have valid headers but no data.
+#[cfg(any(feature = "xz", feature = "xz-rust"))]
+fn xz_stream_with_sizes(sizes: &[u64]) -> Vec<u8> {
+    use crate::format::xz_common::crc32;
+
+    let vli = |out: &mut Vec<u8>, mut value: u64| {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    };
+    // CRC32 check
+    let flags = [0, 1];
+    let mut out = b"\xfd7zXZ\x00".to_vec();
+    out.extend(flags);
+    out.extend(crc32(&flags).to_le_bytes());
+    // one lzma2 filter
+    let block_header = [2, 0, 0x21, 1, 0, 0, 0, 0];
+    let mut index = vec![0];
+    vli(&mut index, sizes.len() as u64);
+    for size in sizes {
+        out.extend(block_header);
+        out.extend(crc32(&block_header).to_le_bytes());
+        out.extend([0; 4]);
+        vli(&mut index, 16);
+        vli(&mut index, *size);
+    }
+    while index.len() % 4 != 0 {
+        index.push(0);
+    }
+    index.extend(crc32(&index).to_le_bytes());
+    out.extend(&index);
+    let mut footer = ((index.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+    footer.extend(flags);
+    out.extend(crc32(&footer).to_le_bytes());
+    out.extend(footer);
+    out.extend(b"YZ");
+    out
 }
 
 fn test_concatenated_inputs(cases: &HashMap<&str, &[u8]>) {
@@ -253,8 +1816,21 @@ fn get_expected(name: &str) -> Vec<u8> {
     gunzip(GZIP_FIXTURES.get(name).unwrap())
 }
 
+/// Generate reproducible text that compresses poorly enough to fill
+/// several bzip2 blocks at level 1.
+#[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+fn pseudo_random_text(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            b"abcdefgh"[(state >> 28) as usize % 8]
+        })
+        .collect()
+}
+
 fn gunzip(data: &[u8]) -> Vec<u8> {
     let mut ret = Vec::new();
-    GzDecoder::new(&*data).read_to_end(&mut ret).unwrap();
+    GzDecoder::new(data).read_to_end(&mut ret).unwrap();
     ret
 }
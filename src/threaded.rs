@@ -0,0 +1,146 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Reader that runs a DecompressReader on a worker thread, so that source
+// I/O and decompression overlap with the consumer's own work.  The worker
+// sends the chunks from DecompressReader::next_chunk() through a bounded
+// channel.  Errors,
+// including trailing data errors, are sent through the channel in order
+// and returned from the consumer's next read.
+
+use bytes::{Buf, Bytes};
+use std::fmt;
+use std::io::{self, BufRead, ErrorKind, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::{CompressionFormat, DecompressReader, PeekReader};
+
+/// Number of chunks the worker can queue before blocking.
+const QUEUE_DEPTH: usize = 4;
+
+pub struct ThreadedDecompressReader<R: BufRead + Send + 'static> {
+    format: CompressionFormat,
+    receiver: Option<Receiver<io::Result<Bytes>>>,
+    worker: Option<JoinHandle<DecompressReader<'static, R>>>,
+    // the reader returned by the worker after it exits
+    reader: Option<DecompressReader<'static, R>>,
+    buf: Bytes,
+}
+
+impl<R: BufRead + Send + 'static> fmt::Debug for ThreadedDecompressReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadedDecompressReader")
+            .field("format", &self.format)
+            .field("buf", &self.buf)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Decompressor that reads and decompresses on a worker thread
+impl<R: BufRead + Send + 'static> ThreadedDecompressReader<R> {
+    pub fn new(reader: DecompressReader<'static, R>) -> Self {
+        let format = reader.format();
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+        let worker = thread::spawn(move || work(reader, sender));
+        Self {
+            format,
+            receiver: Some(receiver),
+            worker: Some(worker),
+            reader: None,
+            buf: Bytes::new(),
+        }
+    }
+
+    pub fn format(&self) -> CompressionFormat {
+        self.format
+    }
+
+    /// Stop the worker thread and return the underlying source.  Any data
+    /// the worker has already read from the source, but which hasn't been
+    /// read from this reader, is discarded.
+    pub fn into_inner(mut self) -> io::Result<PeekReader<R>> {
+        // dropping the receiver causes the worker to stop at its next send
+        self.receiver.take();
+        self.join()?;
+        Ok(self.reader.take().ok_or_else(panicked)?.into_inner())
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        if let Some(worker) = self.worker.take() {
+            self.reader = Some(worker.join().map_err(|_| panicked())?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead + Send + 'static> Read for ThreadedDecompressReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        while self.buf.is_empty() {
+            let receiver = match &self.receiver {
+                Some(receiver) => receiver,
+                // EOF or error already reported
+                None => return Ok(0),
+            };
+            match receiver.recv() {
+                Ok(Ok(buf)) => self.buf = buf,
+                Ok(Err(e)) => {
+                    self.receiver.take();
+                    return Err(e);
+                }
+                Err(_) => {
+                    // The worker exited without sending an error.  Make
+                    // sure it didn't panic.
+                    self.receiver.take();
+                    self.join()?;
+                    return Ok(0);
+                }
+            }
+        }
+        let count = self.buf.len().min(out.len());
+        self.buf.copy_to_slice(&mut out[..count]);
+        Ok(count)
+    }
+}
+
+fn panicked() -> io::Error {
+    io::Error::new(ErrorKind::Other, "decompression thread panicked")
+}
+
+fn work<R: BufRead>(
+    mut reader: DecompressReader<'static, R>,
+    sender: SyncSender<io::Result<Bytes>>,
+) -> DecompressReader<'static, R> {
+    loop {
+        match reader.next_chunk() {
+            Ok(Some(chunk)) => {
+                if sender.send(Ok(chunk)).is_err() {
+                    // consumer went away
+                    return reader;
+                }
+            }
+            // EOF
+            Ok(None) => return reader,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                // if the consumer went away, we're done either way
+                let _ = sender.send(Err(e));
+                return reader;
+            }
+        }
+    }
+}
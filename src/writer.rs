@@ -0,0 +1,298 @@
+// Copyright 2022 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Push-based counterpart to DecompressReader.  Compressed data is written
+// in and decompressed data is written out to the sink.  We buffer enough
+// input to sniff the format using the same detection logic as the reader,
+// then hand the input to a format-specific writer.
+
+use enum_dispatch::enum_dispatch;
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+
+use crate::format::*;
+use crate::limits::Limits;
+use crate::{CompressionFormat, DecompressBuilder, PeekReader, Result};
+
+/// Number of bytes needed to detect any supported format.
+const MAX_MAGIC_LEN: usize = 6;
+
+#[enum_dispatch]
+#[derive(Debug)]
+// built once per writer, so the size disparity doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum WriteFormat<'a, W: Write> {
+    Uncompressed(UncompressedWriter<'a, W>),
+    #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+    Bzip2(Bzip2Writer<W>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzipWriter<W>),
+    #[cfg(any(feature = "xz", feature = "xz-rust"))]
+    Xz(XzWriter<W>),
+    #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+    Zstd(ZstdWriter<'a, W>),
+}
+
+#[derive(Debug)]
+enum State<'a, W: Write> {
+    /// Collecting input until we can detect the format.
+    Detecting(Vec<u8>, W),
+    Active(WriteFormat<'a, LimitedSink<W>>),
+    /// Found trailing data, and the config allows it.  Discard the rest.
+    Trailing(WriteFormat<'a, LimitedSink<W>>),
+    /// Placeholder while switching states.
+    Invalid,
+}
+
+#[derive(Debug)]
+pub struct DecompressWriter<'a, W: Write> {
+    config: DecompressBuilder,
+    state: State<'a, W>,
+}
+
+/// Format-sniffing decompressor that accepts compressed data via `Write`
+impl<'a, W: Write> DecompressWriter<'a, W> {
+    pub fn new(sink: W) -> Self {
+        Self::new_full(sink, DecompressBuilder::new())
+    }
+
+    pub(crate) fn new_full(sink: W, config: DecompressBuilder) -> Self {
+        Self {
+            config,
+            state: State::Detecting(Vec::with_capacity(MAX_MAGIC_LEN), sink),
+        }
+    }
+
+    /// Finish decompression and return the sink.  Fails if the compressed
+    /// data was truncated, or if it was followed by trailing data and the
+    /// config doesn't allow that.
+    pub fn finish(mut self) -> Result<W> {
+        if let State::Detecting(..) = self.state {
+            self.start()?;
+        }
+        let writer = match self.state {
+            State::Active(w) | State::Trailing(w) => w,
+            State::Detecting(..) | State::Invalid => unreachable!(),
+        };
+        let (sink, trailing) = writer.finish()?;
+        if trailing && !self.config.trailing_data {
+            return Err(trailing_data_error().into());
+        }
+        Ok(sink.into_inner())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        match &self.state {
+            State::Detecting(_, sink) => sink,
+            State::Active(w) | State::Trailing(w) => w.get_ref().get_ref(),
+            State::Invalid => unreachable!(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match &mut self.state {
+            State::Detecting(_, sink) => sink,
+            State::Active(w) | State::Trailing(w) => w.get_mut().get_mut(),
+            State::Invalid => unreachable!(),
+        }
+    }
+
+    /// Return the detected format, or None if we haven't received enough
+    /// input to detect it yet.
+    pub fn format(&self) -> Option<CompressionFormat> {
+        match &self.state {
+            State::Detecting(..) => None,
+            State::Active(w) | State::Trailing(w) => Some(w.as_primitive()),
+            State::Invalid => unreachable!(),
+        }
+    }
+
+    /// Detect the format from the buffered input and start decompressing.
+    /// If that fails, we stay in the Detecting state with a full header,
+    /// so later writes fail the same way.
+    fn start(&mut self) -> Result<()> {
+        let format = match &self.state {
+            State::Detecting(header, _) => detect(
+                &mut PeekReader::from_parts(header, io::empty()),
+                &self.config,
+            )?,
+            _ => unreachable!(),
+        };
+        // create the writer before giving it the sink, so we keep the sink
+        // if this fails
+        let mut writer = WriteFormat::new(format, LimitedSink::new(&self.config), &self.config)?;
+        let (header, sink) = match mem::replace(&mut self.state, State::Invalid) {
+            State::Detecting(header, sink) => (header, sink),
+            _ => unreachable!(),
+        };
+        writer.get_mut().sink = Some(sink);
+        self.state = State::Active(writer);
+        let mut buf = &header[..];
+        while !buf.is_empty() {
+            let count = self.write_active(buf)?;
+            buf = &buf[count..];
+        }
+        Ok(())
+    }
+
+    fn write_active(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            State::Active(w) => {
+                // count the whole buffer toward the ratio limit while
+                // decompressing it, then only what was consumed
+                let total_in = w.get_ref().total_in;
+                w.get_mut().total_in = total_in + buf.len() as u64;
+                let count = w.write_compressed(buf);
+                w.get_mut().total_in = total_in + count.as_ref().map_or(0, |&c| c as u64);
+                let count = count?;
+                if count > 0 {
+                    return Ok(count);
+                }
+                // The compressed data has ended and we have more input,
+                // which indicates something wrong with the input unless
+                // the caller has asked us to ignore it.
+                if !self.config.trailing_data {
+                    return Err(trailing_data_error());
+                }
+                self.state = match mem::replace(&mut self.state, State::Invalid) {
+                    State::Active(w) => State::Trailing(w),
+                    _ => unreachable!(),
+                };
+                Ok(buf.len())
+            }
+            State::Trailing(_) => Ok(buf.len()),
+            State::Detecting(..) | State::Invalid => unreachable!(),
+        }
+    }
+}
+
+impl<W: Write> Write for DecompressWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let State::Detecting(header, _) = &mut self.state {
+            let count = buf.len().min(MAX_MAGIC_LEN - header.len());
+            header.extend(&buf[..count]);
+            if header.len() == MAX_MAGIC_LEN {
+                self.start()?;
+            }
+            return Ok(count);
+        }
+        self.write_active(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            // nothing to decompress yet
+            State::Detecting(_, sink) => sink.flush(),
+            State::Active(w) | State::Trailing(w) => w.flush(),
+            State::Invalid => unreachable!(),
+        }
+    }
+}
+
+impl<'a, W: Write> WriteFormat<'a, W> {
+    // config is unused if xz and zstd are compiled out
+    #[allow(unused_variables)]
+    fn new(format: CompressionFormat, sink: W, config: &DecompressBuilder) -> io::Result<Self> {
+        use CompressionFormat::*;
+        Ok(match format {
+            Uncompressed => UncompressedWriter::new(sink).into(),
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Bzip2 => Bzip2Writer::new(sink, config).into(),
+            #[cfg(feature = "gzip")]
+            Gzip => GzipWriter::new(sink).into(),
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            Xz => XzWriter::new(sink, config)?.into(),
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Zstd => ZstdWriter::new(sink, config)?.into(),
+        })
+    }
+
+    fn as_primitive(&self) -> CompressionFormat {
+        use CompressionFormat::*;
+        match self {
+            Self::Uncompressed(_) => Uncompressed,
+            #[cfg(any(feature = "bzip2", feature = "bzip2-rust"))]
+            Self::Bzip2(_) => Bzip2,
+            #[cfg(feature = "gzip")]
+            Self::Gzip(_) => Gzip,
+            #[cfg(any(feature = "xz", feature = "xz-rust"))]
+            Self::Xz(_) => Xz,
+            #[cfg(any(feature = "zstd", feature = "zstd-rust"))]
+            Self::Zstd(_) => Zstd,
+        }
+    }
+}
+
+/// Sink wrapper that enforces the config's limits on decompressed output.
+#[derive(Debug)]
+struct LimitedSink<W: Write> {
+    // set once the format writer has been created
+    sink: Option<W>,
+    limits: Limits,
+    // compressed bytes written to the DecompressWriter
+    total_in: u64,
+    total_out: u64,
+}
+
+impl<W: Write> LimitedSink<W> {
+    fn new(config: &DecompressBuilder) -> Self {
+        Self {
+            sink: None,
+            limits: Limits::new(config),
+            total_in: 0,
+            total_out: 0,
+        }
+    }
+
+    fn get_ref(&self) -> &W {
+        self.sink.as_ref().unwrap()
+    }
+
+    fn get_mut(&mut self) -> &mut W {
+        self.sink.as_mut().unwrap()
+    }
+
+    fn into_inner(self) -> W {
+        self.sink.unwrap()
+    }
+}
+
+impl<W: Write> Write for LimitedSink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // write up to the size limit, then fail
+        let len = buf.len().min(self.limits.remaining(self.total_out)).max(1);
+        self.limits
+            .check(self.total_in, self.total_out + len as u64)?;
+        let count = self.get_mut().write(&buf[..len])?;
+        self.total_out += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.get_mut().flush()
+    }
+}
+
+fn trailing_data_error() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "found trailing data after compressed stream",
+    )
+}